@@ -0,0 +1,92 @@
+//! Readers walking a hart's queue while that hart keeps picking and putting
+//! back, with the queue behind a mutex and behind a reader-writer lock.
+//!
+//! Run with `cargo run --release --example rw_contention --features rwlock`.
+
+use smpscheduler::{
+    FifoTask, ForEachTask, RefFifoScheduler, RefFifoSmpScheduler, RwSmpScheduler, ScheduleHart,
+};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+struct Hart0;
+
+impl ScheduleHart for Hart0 {
+    fn hart_id() -> usize {
+        0
+    }
+}
+
+type Queue = RefFifoScheduler<Arc<FifoTask<usize>>>;
+
+const TASKS: usize = 64;
+const RUN: Duration = Duration::from_millis(500);
+
+fn walk(queue: &Queue) {
+    let mut count = 0;
+    queue.for_each_task(|_| count += 1);
+    black_box(count);
+}
+
+/// Runs `readers` threads calling `read` against one calling `write` for
+/// `RUN`, and returns the reads per second.
+fn measure(readers: usize, read: impl Fn() + Sync, write: impl Fn() + Sync) -> u128 {
+    let stop = AtomicBool::new(false);
+    let reads: u128 = thread::scope(|scope| {
+        let counts: Vec<_> = (0..readers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut count = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        read();
+                        count += 1;
+                    }
+                    count
+                })
+            })
+            .collect();
+        scope.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                write();
+            }
+        });
+        thread::sleep(RUN);
+        stop.store(true, Ordering::Relaxed);
+        counts.into_iter().map(|count| count.join().unwrap()).sum()
+    });
+    reads * 1000 / RUN.as_millis()
+}
+
+fn main() {
+    let mutex = RefFifoSmpScheduler::<1, usize, spin::Mutex<()>, Hart0>::new();
+    let rw = RwSmpScheduler::<1, Queue, spin::RwLock<()>, Hart0>::new(vec![Queue::new()]);
+    mutex.init();
+    rw.init();
+    for i in 0..TASKS {
+        mutex.add_task(Arc::new(FifoTask::new(i)));
+        rw.add_task(Arc::new(FifoTask::new(i)));
+    }
+    println!("readers  mutex reads/s  rwlock reads/s");
+    for readers in [1, 2, 4, 8] {
+        let locked = measure(
+            readers,
+            || mutex.with_local(0, walk),
+            || {
+                let task = mutex.pick_next_task().unwrap();
+                mutex.put_prev_task(task, false);
+            },
+        );
+        let shared = measure(
+            readers,
+            || rw.with_local(0, walk),
+            || {
+                let task = rw.pick_next_task().unwrap();
+                rw.put_prev_task(task, false);
+            },
+        );
+        println!("{readers:>7}  {locked:>13}  {shared:>14}");
+    }
+}
@@ -0,0 +1,72 @@
+use crate::smp::SmpScheduler;
+use crate::ScheduleHart;
+use core::marker::PhantomData;
+use scheduler::BaseScheduler;
+
+/// The current hart's view of a scheduler, with the hart id read once.
+///
+/// Obtained with `enter()`. Reading the hart id may cost a register read and
+/// a fence; a hot loop doing many operations on the same hart can go through
+/// a context which reads it only once. The context must only be used on the
+/// hart which entered it, so it is neither `Send` nor `Sync`, and must not
+/// outlive a migration of the calling thread.
+pub struct HartContext<
+    'a,
+    const SMP: usize,
+    S: BaseScheduler,
+    L: lock_api::RawMutex,
+    H: ScheduleHart,
+> {
+    sched: &'a SmpScheduler<SMP, S, L, H>,
+    hart_id: usize,
+    // pinned to the hart which entered it
+    local: PhantomData<*const ()>,
+}
+
+impl<'a, const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
+    HartContext<'a, SMP, S, L, H>
+{
+    pub(crate) fn new(sched: &'a SmpScheduler<SMP, S, L, H>, hart_id: usize) -> Self {
+        Self {
+            sched,
+            hart_id,
+            local: PhantomData,
+        }
+    }
+
+    /// The id of the hart the context was entered on.
+    pub fn hart_id(&self) -> usize {
+        self.hart_id
+    }
+
+    /// Adds a task to the current hart, as `add_task` does.
+    pub fn add_task(&self, task: S::SchedItem) {
+        self.sched.add_task_as(self.hart_id, self.hart_id, task);
+    }
+
+    /// Removes a task from the current hart, as `remove_task` does.
+    pub fn remove_task(&self, task: &S::SchedItem) -> Option<S::SchedItem> {
+        self.sched.remove_task_on(self.hart_id, task)
+    }
+
+    /// Picks the next task of the current hart, as `pick_next_task` does.
+    pub fn pick_next_task(&self) -> Option<S::SchedItem> {
+        self.sched.pick_next_task_on(self.hart_id)
+    }
+
+    /// Puts the current task back, as `put_prev_task` does.
+    pub fn put_prev_task(&self, prev: S::SchedItem, preempt: bool) {
+        self.sched.put_prev_on(self.hart_id, prev, preempt);
+    }
+
+    /// Ticks the current task, as `task_tick` does.
+    pub fn task_tick(&self, current: &S::SchedItem) -> bool {
+        self.sched.task_tick_on(self.hart_id, current)
+    }
+
+    /// Sets the priority of a task of the current hart, as `set_priority`
+    /// does.
+    pub fn set_priority(&self, task: &S::SchedItem, prio: isize) -> bool {
+        self.sched.set_priority_on(self.hart_id, task, prio)
+    }
+}
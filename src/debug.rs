@@ -0,0 +1,65 @@
+//! A global registry of the live schedulers, for debuggers and panic
+//! handlers which need to dump all of them.
+//!
+//! Every scheduler registers the lock-free state of its harts when it is
+//! created, and unregisters it when dropped. The state lives on the heap and
+//! is shared with the registry, so it stays put while the scheduler itself
+//! is moved around.
+
+use crate::smp::HartState;
+use crate::HartSnapshot;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A scheduler listed in the registry, see [`for_each_scheduler`].
+pub struct LiveScheduler {
+    id: usize,
+    name: &'static str,
+    harts: Arc<[HartState]>,
+}
+
+impl LiveScheduler {
+    /// A number unique to the scheduler, clones included.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The type name of the inner scheduler.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Captures the state of every hart, as `snapshot()` does.
+    pub fn harts(&self) -> impl Iterator<Item = HartSnapshot> + '_ {
+        self.harts
+            .iter()
+            .enumerate()
+            .map(|(id, hart)| hart.snapshot(id))
+    }
+}
+
+static LIVE: lock_api::Mutex<spin::Mutex<()>, Vec<LiveScheduler>> =
+    lock_api::Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn register(name: &'static str, harts: Arc<[HartState]>) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    LIVE.lock().push(LiveScheduler { id, name, harts });
+    id
+}
+
+pub(crate) fn unregister(id: usize) {
+    LIVE.lock().retain(|live| live.id != id);
+}
+
+/// Calls `f` with every live scheduler, oldest first.
+///
+/// The registry stays locked meanwhile, so `f` must not create or drop a
+/// scheduler. A panic handler should not rely on this if the panic may have
+/// hit while a scheduler was being created or dropped.
+pub fn for_each_scheduler(mut f: impl FnMut(&LiveScheduler)) {
+    for live in LIVE.lock().iter() {
+        f(live);
+    }
+}
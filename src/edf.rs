@@ -0,0 +1,150 @@
+use crate::smp::SmpScheduler;
+use crate::ScheduleHart;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use scheduler::BaseScheduler;
+
+/// A task carrying the absolute deadline it must run by.
+pub struct EdfTask<T> {
+    inner: T,
+    deadline: u64,
+    seq: AtomicUsize,
+}
+
+impl<T> EdfTask<T> {
+    /// Creates a new [`EdfTask`] from the inner task struct and its deadline.
+    pub const fn new(inner: T, deadline: u64) -> Self {
+        Self {
+            inner,
+            deadline,
+            seq: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a reference to the inner task struct.
+    pub const fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the deadline of the task.
+    pub const fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    fn key(&self) -> (u64, usize) {
+        (self.deadline, self.seq.load(Ordering::Relaxed))
+    }
+}
+
+impl<T> Deref for EdfTask<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// An earliest-deadline-first scheduler.
+///
+/// Tasks with the same deadline are picked in the order they were queued.
+pub struct EdfScheduler<T> {
+    ready_queue: BTreeMap<(u64, usize), Arc<EdfTask<T>>>,
+    seq: usize,
+}
+
+impl<T> EdfScheduler<T> {
+    /// Creates a new empty [`EdfScheduler`].
+    pub const fn new() -> Self {
+        Self {
+            ready_queue: BTreeMap::new(),
+            seq: 0,
+        }
+    }
+
+    /// get the name of scheduler
+    pub fn scheduler_name() -> &'static str {
+        "Earliest Deadline First"
+    }
+}
+
+impl<T> BaseScheduler for EdfScheduler<T> {
+    type SchedItem = Arc<EdfTask<T>>;
+
+    fn init(&mut self) {}
+
+    fn add_task(&mut self, task: Self::SchedItem) {
+        self.seq = self.seq.wrapping_add(1);
+        task.seq.store(self.seq, Ordering::Relaxed);
+        self.ready_queue.insert(task.key(), task);
+    }
+
+    fn remove_task(&mut self, task: &Self::SchedItem) -> Option<Self::SchedItem> {
+        match self.ready_queue.get(&task.key()) {
+            Some(queued) if Arc::ptr_eq(queued, task) => self.ready_queue.remove(&task.key()),
+            _ => None,
+        }
+    }
+
+    fn pick_next_task(&mut self) -> Option<Self::SchedItem> {
+        self.ready_queue.pop_first().map(|(_, task)| task)
+    }
+
+    fn put_prev_task(&mut self, prev: Self::SchedItem, _preempt: bool) {
+        self.add_task(prev);
+    }
+
+    fn task_tick(&mut self, current: &Self::SchedItem) -> bool {
+        // reschedule as soon as a more urgent task is waiting
+        self.ready_queue
+            .first_key_value()
+            .is_some_and(|((deadline, _), _)| *deadline < current.deadline)
+    }
+
+    fn set_priority(&mut self, _task: &Self::SchedItem, _prio: isize) -> bool {
+        false
+    }
+}
+
+/// An earliest-deadline-first smpscheduler.
+///
+/// Thieves take the earliest-deadline task of their victim.
+pub struct EdfSmpScheduler<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> {
+    inner: SmpScheduler<SMP, EdfScheduler<T>, L, H>,
+}
+
+impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> EdfSmpScheduler<SMP, T, L, H> {
+    /// The number of harts, usable in const contexts.
+    pub const NR_HARTS: usize = SMP;
+
+    /// Creates a new empty [`EdfScheduler`].
+    pub fn new() -> Self {
+        let mut schedulers = Vec::new();
+        for _ in 0..SMP {
+            schedulers.push(EdfScheduler::new());
+        }
+        Self {
+            inner: SmpScheduler::new(schedulers),
+        }
+    }
+}
+
+impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> Deref
+    for EdfSmpScheduler<SMP, T, L, H>
+{
+    type Target = SmpScheduler<SMP, EdfScheduler<T>, L, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> DerefMut
+    for EdfSmpScheduler<SMP, T, L, H>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
@@ -0,0 +1,176 @@
+use crate::local::LocalScheduler;
+#[cfg(feature = "rwlock")]
+use crate::rw::RwSmpScheduler;
+use crate::smp::SmpScheduler;
+use crate::ScheduleHart;
+use alloc::collections::TryReserveError;
+use scheduler::BaseScheduler;
+
+/// The method surface shared by the scheduler containers
+///
+/// Implemented by the smpscheduler behind every wrapper (reach it with
+/// `&*wrapper`), by [`LocalScheduler`], and by `RwSmpScheduler` with the
+/// `rwlock` feature, so code can be written once for any of them. The inherent methods of the same names stay available.
+pub trait SchedulerExt {
+    /// the task handle type
+    type Item;
+
+    /// initialize every queue, before anything else
+    fn init(&self);
+
+    /// add a task to the current hart
+    fn add_task(&self, task: Self::Item);
+
+    /// remove a task from the current hart
+    fn remove_task(&self, task: &Self::Item) -> Option<Self::Item>;
+
+    /// pick the next task to run on the current hart
+    fn pick_next_task(&self) -> Option<Self::Item>;
+
+    /// put the current task back after it was preempted or yielded
+    fn put_prev_task(&self, prev: Self::Item, preempt: bool);
+
+    /// tick the current task, returning whether to reschedule
+    fn task_tick(&self, current: &Self::Item) -> bool;
+
+    /// set the priority of a task
+    fn set_priority(&self, task: &Self::Item, prio: isize) -> bool;
+
+    /// get the number of tasks queued across all harts
+    fn total_len(&self) -> usize;
+}
+
+/// An inner scheduler whose queue can grow fallibly
+///
+/// Lets `add_task_fallible` reserve room for a task before queueing it, so
+/// running out of memory hands the task back instead of aborting.
+/// Implemented by `RefFifoScheduler` with the `fifo` feature, and by
+/// `WeightedLanes` over such schedulers with the `lanes` feature.
+pub trait TryReserve: BaseScheduler {
+    /// make room for `task`, such that adding it next does not allocate
+    fn try_reserve_for(&mut self, task: &Self::SchedItem) -> Result<(), TryReserveError>;
+}
+
+/// An inner scheduler whose queue can be walked through a shared reference
+///
+/// Lets `RwSmpScheduler::for_each_task` visit a queue under its read lock,
+/// where the `BaseScheduler` methods all need the write lock. Implemented by
+/// `RefFifoScheduler` with the `fifo` feature, and by `WeightedLanes` over
+/// such schedulers with the `lanes` feature.
+pub trait ForEachTask: BaseScheduler {
+    /// call `f` on every queued task
+    fn for_each_task(&self, f: impl FnMut(&Self::SchedItem));
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart> SchedulerExt
+    for SmpScheduler<SMP, S, L, H>
+{
+    type Item = S::SchedItem;
+
+    fn init(&self) {
+        SmpScheduler::init(self);
+    }
+
+    fn add_task(&self, task: Self::Item) {
+        SmpScheduler::add_task(self, task);
+    }
+
+    fn remove_task(&self, task: &Self::Item) -> Option<Self::Item> {
+        SmpScheduler::remove_task(self, task)
+    }
+
+    fn pick_next_task(&self) -> Option<Self::Item> {
+        SmpScheduler::pick_next_task(self)
+    }
+
+    fn put_prev_task(&self, prev: Self::Item, preempt: bool) {
+        SmpScheduler::put_prev_task(self, prev, preempt);
+    }
+
+    fn task_tick(&self, current: &Self::Item) -> bool {
+        SmpScheduler::task_tick(self, current)
+    }
+
+    fn set_priority(&self, task: &Self::Item, prio: isize) -> bool {
+        SmpScheduler::set_priority(self, task, prio)
+    }
+
+    fn total_len(&self) -> usize {
+        crate::stats::wrapping_sum((0..SMP).map(|i| self.queue_len(i)))
+    }
+}
+
+impl<S: BaseScheduler, L: lock_api::RawMutex> SchedulerExt for LocalScheduler<S, L> {
+    type Item = S::SchedItem;
+
+    fn init(&self) {
+        LocalScheduler::init(self);
+    }
+
+    fn add_task(&self, task: Self::Item) {
+        LocalScheduler::add_task(self, task);
+    }
+
+    fn remove_task(&self, task: &Self::Item) -> Option<Self::Item> {
+        LocalScheduler::remove_task(self, task)
+    }
+
+    fn pick_next_task(&self) -> Option<Self::Item> {
+        LocalScheduler::pick_next_task(self)
+    }
+
+    fn put_prev_task(&self, prev: Self::Item, preempt: bool) {
+        LocalScheduler::put_prev_task(self, prev, preempt);
+    }
+
+    fn task_tick(&self, current: &Self::Item) -> bool {
+        LocalScheduler::task_tick(self, current)
+    }
+
+    fn set_priority(&self, task: &Self::Item, prio: isize) -> bool {
+        LocalScheduler::set_priority(self, task, prio)
+    }
+
+    fn total_len(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "rwlock")]
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawRwLock, H: ScheduleHart> SchedulerExt
+    for RwSmpScheduler<SMP, S, L, H>
+{
+    type Item = S::SchedItem;
+
+    fn init(&self) {
+        RwSmpScheduler::init(self);
+    }
+
+    fn add_task(&self, task: Self::Item) {
+        RwSmpScheduler::add_task(self, task);
+    }
+
+    fn remove_task(&self, task: &Self::Item) -> Option<Self::Item> {
+        RwSmpScheduler::remove_task(self, task)
+    }
+
+    fn pick_next_task(&self) -> Option<Self::Item> {
+        RwSmpScheduler::pick_next_task(self)
+    }
+
+    fn put_prev_task(&self, prev: Self::Item, preempt: bool) {
+        RwSmpScheduler::put_prev_task(self, prev, preempt);
+    }
+
+    fn task_tick(&self, current: &Self::Item) -> bool {
+        RwSmpScheduler::task_tick(self, current)
+    }
+
+    fn set_priority(&self, task: &Self::Item, prio: isize) -> bool {
+        RwSmpScheduler::set_priority(self, task, prio)
+    }
+
+    fn total_len(&self) -> usize {
+        RwSmpScheduler::total_len(self)
+    }
+}
@@ -0,0 +1,165 @@
+use crate::smp::SmpScheduler;
+use crate::{ForEachTask, ScheduleHart, TryReserve};
+use alloc::collections::{TryReserveError, VecDeque};
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use scheduler::{BaseScheduler, FifoTask};
+
+/// A cloneable handle to a task, such as [`Arc`] or [`Rc`].
+pub trait SchedItemRef: Clone + Deref {
+    /// Returns true if both handles point to the same task.
+    fn same(a: &Self, b: &Self) -> bool;
+}
+
+impl<T> SchedItemRef for Arc<T> {
+    fn same(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(a, b)
+    }
+}
+
+impl<T> SchedItemRef for Rc<T> {
+    fn same(a: &Self, b: &Self) -> bool {
+        Rc::ptr_eq(a, b)
+    }
+}
+
+/// Pinned handles, such as `Pin<Arc<_>>` for tasks which must not move,
+/// work like the handle they wrap: the scheduler only ever moves the handle,
+/// never the task behind it.
+impl<P: SchedItemRef> SchedItemRef for Pin<P> {
+    fn same(a: &Self, b: &Self) -> bool {
+        core::ptr::eq(&**a, &**b)
+    }
+}
+
+/// A FIFO scheduler generic over the handle type of its tasks.
+pub struct RefFifoScheduler<R: SchedItemRef> {
+    ready_queue: VecDeque<R>,
+}
+
+impl<R: SchedItemRef> RefFifoScheduler<R> {
+    /// Creates a new empty [`RefFifoScheduler`].
+    pub const fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+
+    /// get the name of scheduler
+    pub fn scheduler_name() -> &'static str {
+        "FIFO"
+    }
+}
+
+impl<R: SchedItemRef> BaseScheduler for RefFifoScheduler<R> {
+    type SchedItem = R;
+
+    fn init(&mut self) {}
+
+    fn add_task(&mut self, task: Self::SchedItem) {
+        self.ready_queue.push_back(task);
+    }
+
+    fn remove_task(&mut self, task: &Self::SchedItem) -> Option<Self::SchedItem> {
+        let idx = self.ready_queue.iter().position(|t| R::same(t, task))?;
+        self.ready_queue.remove(idx)
+    }
+
+    fn pick_next_task(&mut self) -> Option<Self::SchedItem> {
+        self.ready_queue.pop_front()
+    }
+
+    fn put_prev_task(&mut self, prev: Self::SchedItem, _preempt: bool) {
+        self.ready_queue.push_back(prev);
+    }
+
+    fn task_tick(&mut self, _current: &Self::SchedItem) -> bool {
+        false // no reschedule
+    }
+
+    fn set_priority(&mut self, _task: &Self::SchedItem, _prio: isize) -> bool {
+        false
+    }
+}
+
+impl<R: SchedItemRef> TryReserve for RefFifoScheduler<R> {
+    fn try_reserve_for(&mut self, _task: &Self::SchedItem) -> Result<(), TryReserveError> {
+        self.ready_queue.try_reserve(1)
+    }
+}
+
+impl<R: SchedItemRef> ForEachTask for RefFifoScheduler<R> {
+    fn for_each_task(&self, f: impl FnMut(&Self::SchedItem)) {
+        self.ready_queue.iter().for_each(f);
+    }
+}
+
+/// A FIFO smpscheduler generic over the task handle `R`.
+///
+/// `R` defaults to [`Arc`], which is required as soon as the queues are
+/// shared between harts. A partition driven by a single hart can use [`Rc`]
+/// instead and skip the atomic reference counting; such a scheduler is
+/// neither `Send` nor `Sync`.
+pub struct RefFifoSmpScheduler<
+    const SMP: usize,
+    T,
+    L: lock_api::RawMutex,
+    H: ScheduleHart,
+    R: SchedItemRef<Target = FifoTask<T>> = Arc<FifoTask<T>>,
+> {
+    inner: SmpScheduler<SMP, RefFifoScheduler<R>, L, H>,
+}
+
+impl<
+        const SMP: usize,
+        T,
+        L: lock_api::RawMutex,
+        H: ScheduleHart,
+        R: SchedItemRef<Target = FifoTask<T>>,
+    > RefFifoSmpScheduler<SMP, T, L, H, R>
+{
+    /// The number of harts, usable in const contexts.
+    pub const NR_HARTS: usize = SMP;
+
+    /// Creates a new empty [`RefFifoScheduler`].
+    pub fn new() -> Self {
+        let mut schedulers = Vec::new();
+        for _ in 0..SMP {
+            schedulers.push(RefFifoScheduler::new());
+        }
+        Self {
+            inner: SmpScheduler::new(schedulers),
+        }
+    }
+}
+
+impl<
+        const SMP: usize,
+        T,
+        L: lock_api::RawMutex,
+        H: ScheduleHart,
+        R: SchedItemRef<Target = FifoTask<T>>,
+    > Deref for RefFifoSmpScheduler<SMP, T, L, H, R>
+{
+    type Target = SmpScheduler<SMP, RefFifoScheduler<R>, L, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<
+        const SMP: usize,
+        T,
+        L: lock_api::RawMutex,
+        H: ScheduleHart,
+        R: SchedItemRef<Target = FifoTask<T>>,
+    > DerefMut for RefFifoSmpScheduler<SMP, T, L, H, R>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
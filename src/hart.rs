@@ -0,0 +1,99 @@
+use crate::ScheduleHart;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The trait for finding the storage of the current core
+///
+/// Implemented by the kernel, typically on top of a per-core area addressed
+/// by a register such as `tp` or `gs`. Every core must get its own slot.
+pub trait HartSlot {
+    /// get the hart id slot of the core running the caller
+    fn slot() -> &'static AtomicUsize;
+}
+
+/// A [`ScheduleHart`] reading the hart id from per-core storage.
+///
+/// Each hart calls [`register`](RegisterHart::register) once early in its
+/// boot, before it touches the scheduler.
+pub struct RegisterHart<C: HartSlot>(PhantomData<C>);
+
+impl<C: HartSlot> RegisterHart<C> {
+    /// Records `hart_id` as the id of the calling core.
+    pub fn register(hart_id: usize) {
+        C::slot().store(hart_id, Ordering::Relaxed);
+    }
+}
+
+impl<C: HartSlot> ScheduleHart for RegisterHart<C> {
+    fn hart_id() -> usize {
+        C::slot().load(Ordering::Relaxed)
+    }
+}
+
+/// A [`ScheduleHart`] reading the hart id from a thread local, for running
+/// one OS thread per hart.
+///
+/// Each thread calls [`register`](ThreadLocalHart::register) before it
+/// touches the scheduler; threads which never do read as hart 0.
+#[cfg(feature = "std")]
+pub struct ThreadLocalHart;
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static THREAD_HART: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+#[cfg(feature = "std")]
+impl ThreadLocalHart {
+    /// Records `hart_id` as the id of the calling thread.
+    pub fn register(hart_id: usize) {
+        THREAD_HART.with(|hart| hart.set(hart_id));
+    }
+}
+
+#[cfg(feature = "std")]
+impl ScheduleHart for ThreadLocalHart {
+    fn hart_id() -> usize {
+        THREAD_HART.with(|hart| hart.get())
+    }
+}
+
+/// Defines a [`ScheduleHart`](crate::ScheduleHart) whose hart id is the value
+/// of an expression, e.g. a read of `mhartid` or `tp`.
+///
+/// ```
+/// # use smpscheduler::{register_hart, ScheduleHart};
+/// fn read_tp() -> usize {
+///     1
+/// }
+/// register_hart!(pub TpHart, read_tp(), 2);
+/// assert_eq!(TpHart::hart_id(), 1);
+/// assert_eq!(TpHart::hart_count(), Some(2));
+/// ```
+///
+/// The optional third argument is the number of harts, checked by `init()`.
+#[macro_export]
+macro_rules! register_hart {
+    ($vis:vis $name:ident, $id:expr) => {
+        $vis struct $name;
+
+        impl $crate::ScheduleHart for $name {
+            fn hart_id() -> usize {
+                $id
+            }
+        }
+    };
+    ($vis:vis $name:ident, $id:expr, $count:expr) => {
+        $vis struct $name;
+
+        impl $crate::ScheduleHart for $name {
+            fn hart_id() -> usize {
+                $id
+            }
+
+            fn hart_count() -> Option<usize> {
+                Some($count)
+            }
+        }
+    };
+}
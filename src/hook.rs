@@ -0,0 +1,87 @@
+/// The trait for blocking an idle hart until work shows up
+///
+/// Used by `wait_for_task`: the scheduler parks a hart which found nothing to
+/// run and unparks it when a task is enqueued.
+pub trait Parker {
+    /// block the calling hart until [`unpark`](Parker::unpark) is called for it
+    ///
+    /// A wakeup delivered before the hart parks must not be lost: the next
+    /// `park` then returns right away.
+    fn park(&self, hart_id: usize);
+
+    /// wake up `hart_id`, or make its next `park` return immediately
+    fn unpark(&self, hart_id: usize);
+}
+
+/// The trait for waking a hart which was given work, e.g. with an IPI
+///
+/// Called after every enqueue onto another hart than the current one, once
+/// the queue lock has been released.
+pub trait WakeHart {
+    /// `hart_id` got a task and now has `new_len` queued, as read from its
+    /// length counter right after the enqueue
+    fn wake(&self, hart_id: usize, new_len: usize);
+}
+
+/// The trait for pulling work from outside the scheduler
+///
+/// Asked for a task when a hart found nothing to run locally and nothing to
+/// steal, e.g. to drain a global inbox or an I/O completion queue.
+pub trait FallbackSource<T> {
+    /// supply a task for `hart_id` to run, if there is one
+    fn supply(&self, hart_id: usize) -> Option<T>;
+}
+
+/// The trait for vetoing the migration of a task by looking at the task
+///
+/// Consulted for every task a hart is about to steal; a task it refuses is
+/// left exactly where it was in its queue, and the thief looks at the tasks
+/// behind it before moving on to the next hart.
+pub trait StealFilter<T> {
+    /// whether `item` may move from hart `from` to hart `to`
+    fn can_steal(&self, item: &T, from: usize, to: usize) -> bool;
+}
+
+/// The trait for taking back tasks the scheduler lets go of
+///
+/// Handed every task the scheduler would otherwise drop: those discarded by
+/// an overflow policy or by `reinit`, emptied out by `clear`, or still queued
+/// when the scheduler is dropped. A sink can put them on a free list for reuse.
+pub trait TaskSink<T> {
+    /// take ownership of `task`, which is no longer queued anywhere
+    fn recycle(&self, task: T);
+}
+
+/// A [`Parker`] built on `std` mutexes and condition variables.
+#[cfg(feature = "std")]
+pub struct StdParker {
+    harts: std::vec::Vec<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+}
+
+#[cfg(feature = "std")]
+impl StdParker {
+    /// Creates a new [`StdParker`] for `harts` harts.
+    pub fn new(harts: usize) -> Self {
+        Self {
+            harts: (0..harts).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Parker for StdParker {
+    fn park(&self, hart_id: usize) {
+        let (token, cvar) = &self.harts[hart_id];
+        let mut token = token.lock().unwrap();
+        while !*token {
+            token = cvar.wait(token).unwrap();
+        }
+        *token = false;
+    }
+
+    fn unpark(&self, hart_id: usize) {
+        let (token, cvar) = &self.harts[hart_id];
+        *token.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+}
@@ -0,0 +1,96 @@
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The trait for masking interrupts on the current hart
+pub trait IrqControl {
+    /// disable interrupts, returning the state to restore later
+    fn save_and_disable() -> usize;
+
+    /// restore a state returned by [`save_and_disable`](IrqControl::save_and_disable)
+    fn restore(state: usize);
+}
+
+/// Leaves interrupts alone, for [`AtomicRawMutex`].
+pub struct NoIrq;
+
+impl IrqControl for NoIrq {
+    fn save_and_disable() -> usize {
+        0
+    }
+
+    fn restore(_state: usize) {}
+}
+
+/// A plain spinning raw mutex, for schedulers never entered from interrupt
+/// context.
+///
+/// This is [`IrqSafeMutex`] without the interrupt masking, and needs no
+/// dependency beyond `core`, unlike `spin::Mutex<()>` which serves the same
+/// purpose.
+pub type AtomicRawMutex = IrqSafeMutex<NoIrq>;
+
+/// A spinning raw mutex which keeps interrupts disabled while it is held.
+///
+/// Use it as the `L` parameter when the scheduler is also entered from
+/// interrupt context (e.g. the timer calling `task_tick`): with a plain spin
+/// lock, an interrupt arriving while its own hart holds a queue would spin on
+/// that queue forever. When every call comes from thread context, a plain
+/// spin lock such as [`AtomicRawMutex`] is cheaper; hosted tests and
+/// simulations can use any `RawMutex`, `std` based ones included.
+///
+/// Each lock restores the interrupt state it found, so nested guards must be
+/// released in the reverse order they were taken: releasing the outer one
+/// first would turn interrupts back on while the inner one is still held.
+/// The scheduler does so wherever it holds several queues at once.
+pub struct IrqSafeMutex<I: IrqControl> {
+    locked: AtomicBool,
+    /// the interrupt state of the holder, only touched by the holder
+    saved: AtomicUsize,
+    irq: PhantomData<fn() -> I>,
+}
+
+unsafe impl<I: IrqControl> lock_api::RawMutex for IrqSafeMutex<I> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+        saved: AtomicUsize::new(0),
+        irq: PhantomData,
+    };
+
+    // the interrupt state belongs to the hart which took the lock
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let state = I::save_and_disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.saved.store(state, Ordering::Relaxed);
+            true
+        } else {
+            I::restore(state);
+            false
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let state = self.saved.load(Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        I::restore(state);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::smp::SmpScheduler;
+use crate::{ForEachTask, ScheduleHart, TryReserve};
+use alloc::collections::TryReserveError;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use scheduler::BaseScheduler;
+
+/// Several inner schedulers, the lanes, served by weighted round robin.
+///
+/// Each task belongs to the lane `lane_of` maps it to. Picks go to the lanes
+/// in turn, lane `i` being served up to `weights[i]` times in a row before
+/// the next one gets its turn; a lane found empty forfeits the rest of its
+/// turn. This shares a hart between task classes in proportion to the
+/// weights, without the bookkeeping of CFS.
+pub struct WeightedLanes<S: BaseScheduler> {
+    lanes: Vec<S>,
+    weights: Vec<usize>,
+    lane_of: fn(&S::SchedItem) -> usize,
+    // the lane being served and the picks left in its turn
+    current: usize,
+    credit: usize,
+}
+
+impl<S: BaseScheduler> WeightedLanes<S> {
+    /// Creates the lanes, `weights[i]` being the weight of `lanes[i]`.
+    pub fn new(lanes: Vec<S>, weights: Vec<usize>, lane_of: fn(&S::SchedItem) -> usize) -> Self {
+        assert!(!lanes.is_empty(), "WeightedLanes needs at least one lane");
+        assert_eq!(lanes.len(), weights.len());
+        assert!(
+            weights.iter().all(|&weight| weight > 0),
+            "lane weights must be positive"
+        );
+        Self {
+            credit: weights[0],
+            lanes,
+            weights,
+            lane_of,
+            current: 0,
+        }
+    }
+
+    /// get the name of scheduler
+    pub fn scheduler_name() -> &'static str {
+        "Weighted lanes"
+    }
+
+    fn lane(&mut self, task: &S::SchedItem) -> &mut S {
+        let lane = (self.lane_of)(task);
+        &mut self.lanes[lane]
+    }
+}
+
+impl<S: BaseScheduler> BaseScheduler for WeightedLanes<S> {
+    type SchedItem = S::SchedItem;
+
+    fn init(&mut self) {
+        for lane in self.lanes.iter_mut() {
+            lane.init();
+        }
+        self.current = 0;
+        self.credit = self.weights[0];
+    }
+
+    fn add_task(&mut self, task: Self::SchedItem) {
+        self.lane(&task).add_task(task);
+    }
+
+    fn remove_task(&mut self, task: &Self::SchedItem) -> Option<Self::SchedItem> {
+        self.lane(task).remove_task(task)
+    }
+
+    fn pick_next_task(&mut self) -> Option<Self::SchedItem> {
+        // the rest of the current turn, then a full turn of every lane
+        for _ in 0..=self.lanes.len() {
+            if self.credit > 0 {
+                if let Some(task) = self.lanes[self.current].pick_next_task() {
+                    self.credit -= 1;
+                    return Some(task);
+                }
+            }
+            self.current = (self.current + 1) % self.lanes.len();
+            self.credit = self.weights[self.current];
+        }
+        None
+    }
+
+    fn put_prev_task(&mut self, prev: Self::SchedItem, preempt: bool) {
+        self.lane(&prev).put_prev_task(prev, preempt);
+    }
+
+    fn task_tick(&mut self, current: &Self::SchedItem) -> bool {
+        self.lane(current).task_tick(current)
+    }
+
+    fn set_priority(&mut self, task: &Self::SchedItem, prio: isize) -> bool {
+        self.lane(task).set_priority(task, prio)
+    }
+}
+
+impl<S: TryReserve> TryReserve for WeightedLanes<S> {
+    fn try_reserve_for(&mut self, task: &Self::SchedItem) -> Result<(), TryReserveError> {
+        self.lane(task).try_reserve_for(task)
+    }
+}
+
+impl<S: ForEachTask> ForEachTask for WeightedLanes<S> {
+    fn for_each_task(&self, mut f: impl FnMut(&Self::SchedItem)) {
+        for lane in self.lanes.iter() {
+            lane.for_each_task(&mut f);
+        }
+    }
+}
+
+/// An smpscheduler whose harts each serve [`WeightedLanes`].
+///
+/// Thieves take what the victim would pick next, so stealing also advances
+/// the victim's round robin.
+pub struct WeightedSmpScheduler<
+    const SMP: usize,
+    S: BaseScheduler,
+    L: lock_api::RawMutex,
+    H: ScheduleHart,
+> {
+    inner: SmpScheduler<SMP, WeightedLanes<S>, L, H>,
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
+    WeightedSmpScheduler<SMP, S, L, H>
+{
+    /// The number of harts, usable in const contexts.
+    pub const NR_HARTS: usize = SMP;
+
+    /// Creates a new empty [`WeightedSmpScheduler`] with one lane per
+    /// weight on every hart, each made by `make`. See [`WeightedLanes`].
+    pub fn new(
+        weights: &[usize],
+        lane_of: fn(&S::SchedItem) -> usize,
+        mut make: impl FnMut() -> S,
+    ) -> Self
+    where
+        S::SchedItem: Deref,
+    {
+        let mut schedulers = Vec::new();
+        for _ in 0..SMP {
+            let lanes = weights.iter().map(|_| make()).collect();
+            schedulers.push(WeightedLanes::new(lanes, weights.to_vec(), lane_of));
+        }
+        Self {
+            inner: SmpScheduler::new(schedulers),
+        }
+    }
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart> Deref
+    for WeightedSmpScheduler<SMP, S, L, H>
+{
+    type Target = SmpScheduler<SMP, WeightedLanes<S>, L, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart> DerefMut
+    for WeightedSmpScheduler<SMP, S, L, H>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
@@ -35,17 +35,86 @@
 //! assert_eq!(*v, 2);
 //!```
 //!
+//! # Choosing the lock
+//!
+//! `L` is the raw mutex guarding each queue. When the scheduler is only
+//! entered from thread context, a spin lock such as `spin::Mutex<()>`, or
+//! `AtomicRawMutex` of the `irq` feature, is enough. When interrupt handlers
+//! enter it too, e.g. a timer calling `task_tick`, use `IrqSafeMutex` of the
+//! `irq` feature, so that a handler cannot spin on a queue its own hart
+//! holds.
+//!
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![deny(missing_docs)]
 
 #[cfg(feature = "cfs")]
 pub use cfs::*;
+pub use context::HartContext;
+#[cfg(feature = "smp-debug")]
+pub use debug::{for_each_scheduler, LiveScheduler};
+#[cfg(feature = "edf")]
+pub use edf::*;
+pub use ext::{ForEachTask, SchedulerExt, TryReserve};
 #[cfg(feature = "fifo")]
 pub use fifo::*;
+#[cfg(feature = "fifo")]
+pub use handle::*;
+#[cfg(feature = "std")]
+pub use hart::ThreadLocalHart;
+pub use hart::{HartSlot, RegisterHart};
+#[cfg(feature = "std")]
+pub use hook::StdParker;
+pub use hook::{FallbackSource, Parker, StealFilter, TaskSink, WakeHart};
+#[cfg(feature = "irq")]
+pub use irq::{AtomicRawMutex, IrqControl, IrqSafeMutex, NoIrq};
+#[cfg(feature = "lanes")]
+pub use lanes::{WeightedLanes, WeightedSmpScheduler};
+pub use local::{LocalHart, LocalScheduler};
+#[cfg(feature = "replay")]
+pub use replay::{ReplayEntry, ReplayOp, REPLAY_LEN};
 #[cfg(feature = "rr")]
 pub use rr::*;
+#[cfg(feature = "rwlock")]
+pub use rw::RwSmpScheduler;
+pub use scheduler::BaseScheduler;
+#[cfg(feature = "debug-checks")]
+pub use smp::ValidationError;
+pub use smp::{OverflowPolicy, Placement, QueueGuards, Same, StealEnd, StealMiss};
+#[cfg(feature = "metrics")]
+pub use stats::HartStats;
+pub use stats::{FairnessReport, HartSnapshot, SchedSnapshot};
+pub use task::{HasTaskMeta, TaskId, TaskMeta};
+#[cfg(feature = "trace")]
+pub use trace::{register_tracepoints, unregister_tracepoints, Tracepoints};
+#[cfg(feature = "weak")]
+pub use weak::*;
+mod context;
+#[cfg(feature = "smp-debug")]
+mod debug;
+#[cfg(feature = "edf")]
+mod edf;
+mod ext;
+#[cfg(feature = "fifo")]
+mod handle;
+mod hart;
+mod hook;
+#[cfg(feature = "irq")]
+mod irq;
+#[cfg(feature = "lanes")]
+mod lanes;
+mod local;
+mod replay;
+#[cfg(feature = "std")]
+mod runtime;
+#[cfg(feature = "rwlock")]
+mod rw;
 mod smp;
+mod stats;
+mod task;
+mod trace;
+#[cfg(feature = "weak")]
+mod weak;
 
 extern crate alloc;
 
@@ -53,22 +122,37 @@ extern crate alloc;
 pub trait ScheduleHart {
     /// get the hart id
     fn hart_id() -> usize;
+
+    /// get the number of harts, if known
+    ///
+    /// When provided, `init()` checks it against the `SMP` the scheduler was
+    /// built for, catching ids that would not fit the configured queues.
+    fn hart_count() -> Option<usize> {
+        None
+    }
 }
 #[cfg(feature = "fifo")]
 mod fifo {
     /// fifo task
     pub type FifoTask<T> = scheduler::FifoTask<T>;
+    /// fifo smpscheduler locked with `spin::Mutex`
+    #[cfg(feature = "spin")]
+    pub type FifoSpin<const SMP: usize, T, H> = FifoSmpScheduler<SMP, T, spin::Mutex<()>, H>;
 
-    use crate::smp::SmpScheduler;
+    use crate::smp::{Same, SmpScheduler};
     use crate::ScheduleHart;
+    use alloc::sync::Arc;
     use alloc::vec::Vec;
     use core::ops::{Deref, DerefMut};
-    use scheduler::FifoScheduler;
+    pub use scheduler::FifoScheduler;
     /// A simple [FIFO] preemptive smpscheduler.
     pub struct FifoSmpScheduler<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> {
         inner: SmpScheduler<SMP, FifoScheduler<T>, L, H>,
     }
     impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> FifoSmpScheduler<SMP, T, L, H> {
+        /// The number of harts, usable in const contexts.
+        pub const NR_HARTS: usize = SMP;
+
         /// Creates a new empty [`FifoScheduler`].
         pub fn new() -> Self {
             let mut schedulers = Vec::new();
@@ -79,6 +163,43 @@ mod fifo {
                 inner: SmpScheduler::new(schedulers),
             }
         }
+
+        /// Creates a new empty [`FifoScheduler`] matching tasks with `same`
+        /// instead of by address, as set by `set_same`.
+        pub fn new_with_same(same: Same<Arc<FifoTask<T>>>) -> Self {
+            let mut this = Self::new();
+            this.inner.set_same(same);
+            this
+        }
+
+        /// Creates a new empty [`FifoScheduler`] whose harts are named for
+        /// diagnostics, `names[i]` being the name of hart `i`.
+        pub fn with_hart_names(names: [&'static str; SMP]) -> Self {
+            let mut schedulers = Vec::new();
+            for _ in 0..SMP {
+                schedulers.push(FifoScheduler::new());
+            }
+            Self {
+                inner: SmpScheduler::with_hart_names(schedulers, names),
+            }
+        }
+
+        /// Adds a task at the head of the current hart's queue, so that it
+        /// runs next, ahead of the tasks queued before it.
+        ///
+        /// This breaks FIFO order on purpose, as a lightweight way to rush a
+        /// task without a priority scheduler. The inner FIFO cannot insert at
+        /// the front, so the queue is drained and refilled under its lock,
+        /// which is O(length).
+        pub fn enqueue_front(&self, task: Arc<FifoTask<T>>) {
+            self.inner.add_task_front(task);
+        }
+
+        /// Adds a task at the tail of the current hart's queue, which is
+        /// what `add_task` does.
+        pub fn enqueue_back(&self, task: Arc<FifoTask<T>>) {
+            self.inner.add_task(task);
+        }
     }
 
     impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> Deref
@@ -104,11 +225,16 @@ mod fifo {
 mod rr {
     /// rr task
     pub type RRTask<T, const MAX_TIME_SLICE: usize> = scheduler::RRTask<T, MAX_TIME_SLICE>;
+    /// rr smpscheduler locked with `spin::Mutex`
+    #[cfg(feature = "spin")]
+    pub type RRSpin<const SMP: usize, const MAX_TIME_SLICE: usize, T, H> =
+        RRSmpScheduler<SMP, MAX_TIME_SLICE, T, spin::Mutex<()>, H>;
 
-    use crate::smp::SmpScheduler;
+    use crate::smp::{Same, SmpScheduler};
     use crate::ScheduleHart;
+    use alloc::sync::Arc;
     use alloc::vec::Vec;
-    use scheduler::RRScheduler;
+    pub use scheduler::RRScheduler;
 
     /// A simple [Round-Robin] (RR) preemptive smpscheduler.
     pub struct RRSmpScheduler<
@@ -129,8 +255,20 @@ mod rr {
             H: ScheduleHart,
         > RRSmpScheduler<SMP, MAX_TIME_SLICE, T, L, H>
     {
+        /// The number of harts, usable in const contexts.
+        pub const NR_HARTS: usize = SMP;
+
         /// Creates a new empty [`RRScheduler`].
+        ///
+        /// A zero `MAX_TIME_SLICE` would reschedule on every tick, so it is
+        /// refused at compile time:
+        ///
+        /// ```compile_fail
+        /// # use smpscheduler::{LocalHart, RRSmpScheduler};
+        /// let rr = RRSmpScheduler::<1, 0, usize, spin::Mutex<()>, LocalHart>::new();
+        /// ```
         pub fn new() -> Self {
+            const { assert!(MAX_TIME_SLICE > 0, "MAX_TIME_SLICE must be positive") };
             let mut schedulers = Vec::new();
             for _ in 0..SMP {
                 schedulers.push(RRScheduler::<T, MAX_TIME_SLICE>::new());
@@ -139,6 +277,27 @@ mod rr {
                 inner: SmpScheduler::new(schedulers),
             }
         }
+
+        /// Creates a new empty [`RRScheduler`] matching tasks with `same`
+        /// instead of by address, as set by `set_same`.
+        pub fn new_with_same(same: Same<Arc<RRTask<T, MAX_TIME_SLICE>>>) -> Self {
+            let mut this = Self::new();
+            this.inner.set_same(same);
+            this
+        }
+
+        /// Creates a new empty [`RRScheduler`] whose harts are named for
+        /// diagnostics, `names[i]` being the name of hart `i`.
+        pub fn with_hart_names(names: [&'static str; SMP]) -> Self {
+            const { assert!(MAX_TIME_SLICE > 0, "MAX_TIME_SLICE must be positive") };
+            let mut schedulers = Vec::new();
+            for _ in 0..SMP {
+                schedulers.push(RRScheduler::new());
+            }
+            Self {
+                inner: SmpScheduler::with_hart_names(schedulers, names),
+            }
+        }
     }
 
     impl<
@@ -173,10 +332,14 @@ mod rr {
 mod cfs {
     /// cfs task
     pub type CFSTask<T> = scheduler::CFSTask<T>;
-    use crate::smp::SmpScheduler;
+    /// cfs smpscheduler locked with `spin::Mutex`
+    #[cfg(feature = "spin")]
+    pub type CFSSpin<const SMP: usize, T, H> = CFSSmpScheduler<SMP, T, spin::Mutex<()>, H>;
+    use crate::smp::{Same, SmpScheduler};
     use crate::ScheduleHart;
+    use alloc::sync::Arc;
     use alloc::vec::Vec;
-    use scheduler::CFScheduler;
+    pub use scheduler::CFScheduler;
 
     /// A simple [Completely Fair Scheduler][1] (CFS).
     pub struct CFSSmpScheduler<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> {
@@ -184,6 +347,9 @@ mod cfs {
     }
 
     impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> CFSSmpScheduler<SMP, T, L, H> {
+        /// The number of harts, usable in const contexts.
+        pub const NR_HARTS: usize = SMP;
+
         /// Creates a new empty [`CFScheduler`].
         pub fn new() -> Self {
             let mut schedulers = Vec::new();
@@ -194,6 +360,26 @@ mod cfs {
                 inner: SmpScheduler::new(schedulers),
             }
         }
+
+        /// Creates a new empty [`CFScheduler`] matching tasks with `same`
+        /// instead of by address, as set by `set_same`.
+        pub fn new_with_same(same: Same<Arc<CFSTask<T>>>) -> Self {
+            let mut this = Self::new();
+            this.inner.set_same(same);
+            this
+        }
+
+        /// Creates a new empty [`CFScheduler`] whose harts are named for
+        /// diagnostics, `names[i]` being the name of hart `i`.
+        pub fn with_hart_names(names: [&'static str; SMP]) -> Self {
+            let mut schedulers = Vec::new();
+            for _ in 0..SMP {
+                schedulers.push(CFScheduler::new());
+            }
+            Self {
+                inner: SmpScheduler::with_hart_names(schedulers, names),
+            }
+        }
     }
 
     impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> core::ops::Deref
@@ -217,8 +403,10 @@ mod cfs {
 
 #[cfg(test)]
 mod tests {
+    use crate::smp::SmpScheduler;
     use crate::{
-        CFSSmpScheduler, CFSTask, FifoSmpScheduler, FifoTask, RRSmpScheduler, RRTask, ScheduleHart,
+        CFSSmpScheduler, CFSTask, FifoSmpScheduler, FifoTask, HasTaskMeta, RRSmpScheduler, RRTask,
+        ScheduleHart, TaskMeta,
     };
     use alloc::sync::Arc;
     use core::sync::atomic::{AtomicUsize, Ordering};
@@ -268,4 +456,2283 @@ mod tests {
         let cfs = CFSSmpScheduler::<2, usize, spin::Mutex<()>, ScheduleHartImpl>::new();
         gen_test!(cfs, CFSTask);
     }
+
+    #[test]
+    fn steal_limit_test() {
+        static HART: AtomicUsize = AtomicUsize::new(0);
+        struct Hart;
+        impl ScheduleHart for Hart {
+            fn hart_id() -> usize {
+                HART.load(Ordering::SeqCst)
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<2, MetaTask, spin::Mutex<()>, Hart>::new();
+        fifo.set_steal_limit(Some(1));
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(MetaTask(TaskMeta::new()))));
+        let task = fifo.pick_next_task_tracked().unwrap(); // steal task from hart 1
+        assert_eq!(task.task_meta().steal_count(), 1);
+        fifo.put_prev_task(task, false);
+        HART.store(1, Ordering::SeqCst);
+        assert!(fifo.pick_next_task_tracked().is_none()); // limit reached, stays on hart 0
+        HART.store(0, Ordering::SeqCst);
+        let task = fifo.pick_next_task_tracked().unwrap();
+        assert_eq!(task.task_meta().steal_count(), 0);
+    }
+
+    #[test]
+    fn balanced_add_tie_test() {
+        let task = || Arc::new(FifoTask::new(MetaTask(TaskMeta::new())));
+        let fifo = FifoSmpScheduler::<3, MetaTask, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(1));
+        fifo.balanced_add(task());
+        assert_eq!(fifo.queue_len(1), 1); // all empty, the current hart wins
+        fifo.balanced_add(task());
+        assert_eq!(fifo.queue_len(0), 1); // then the lowest index
+        HART.with(|h| h.set(2));
+        let picked = fifo.pick_next_task_tracked().unwrap(); // stolen by hart 2
+        assert_eq!(picked.task_meta().last_hart(), Some(2));
+        fifo.pick_next_task_tracked();
+        HART.with(|h| h.set(0));
+        // all empty again, the last hart beats the current one
+        fifo.balanced_add_tracked(picked);
+        assert_eq!(fifo.queue_len(2), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "debug-checks")]
+    fn validate_test() {
+        use crate::ValidationError;
+        use scheduler::BaseScheduler;
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(2)));
+        assert_eq!(fifo.validate(), Ok(()));
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 1);
+        let mut guards = fifo.try_lock_all().unwrap();
+        assert_eq!(fifo.validate(), Err(ValidationError::Busy));
+        // bypass the counters, as a broken extension would
+        guards[1].add_task(Arc::new(FifoTask::new(3)));
+        drop(guards);
+        assert_eq!(
+            fifo.validate(),
+            Err(ValidationError::LenMismatch {
+                hart: 1,
+                urgent: false,
+                counted: 0,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn idle_streak_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(1));
+        assert_eq!(fifo.pick_next_task_or_idle().err(), Some(1));
+        assert_eq!(fifo.pick_next_task_or_idle().err(), Some(2));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        assert_eq!(*fifo.pick_next_task_or_idle().unwrap().inner(), 1);
+        assert_eq!(fifo.pick_next_task_or_idle().err(), Some(1));
+        // streaks are per hart
+        HART.with(|h| h.set(0));
+        assert_eq!(fifo.pick_next_task_or_idle().err(), Some(1));
+    }
+
+    #[test]
+    fn tracked_idle_streak_test() {
+        let fifo = FifoSmpScheduler::<2, MetaTask, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(1));
+        assert_eq!(fifo.pick_next_task_or_idle().err(), Some(1));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(MetaTask(TaskMeta::new()))));
+        let stolen = fifo.pick_next_task_tracked().unwrap();
+        assert_eq!(stolen.task_meta().stolen_from(), Some(0));
+        // the tracked pick goes through the same pipeline
+        assert_eq!(fifo.pick_next_task_or_idle().err(), Some(1));
+    }
+
+    #[test]
+    #[cfg(feature = "smp-debug")]
+    fn live_registry_test() {
+        let lens = |id| {
+            let mut lens = None;
+            crate::for_each_scheduler(|live| {
+                if live.id() == id {
+                    lens = Some(live.harts().map(|hart| hart.len).collect::<Vec<_>>());
+                }
+            });
+            lens
+        };
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        let id = fifo.debug_id();
+        // moving the scheduler keeps the entry valid
+        let moved = alloc::boxed::Box::new(fifo);
+        assert_eq!(lens(id), Some(alloc::vec![0, 1]));
+        let other = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        assert_ne!(other.debug_id(), id);
+        drop(moved);
+        assert_eq!(lens(id), None);
+        assert_eq!(lens(other.debug_id()), Some(alloc::vec![0, 0, 0]));
+    }
+
+    #[test]
+    fn hart_names_test() {
+        let unnamed = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        let hart = unnamed.snapshot().harts[1];
+        assert_eq!(hart.name, None);
+        assert!(std::format!("{hart:?}").starts_with("HartSnapshot { name: hart1,"));
+        let fifo =
+            FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::with_hart_names(["mgmt", "app0"]);
+        fifo.init();
+        assert_eq!(fifo.hart_name(1), Some("app0"));
+        let names = fifo.snapshot().harts.map(|hart| hart.name);
+        assert_eq!(names, [Some("mgmt"), Some("app0")]);
+        let dump = std::format!("{:?}", fifo.snapshot());
+        assert!(dump.contains("name: \"mgmt\"") && dump.contains("name: \"app0\""));
+        #[cfg(feature = "smp-debug")]
+        crate::for_each_scheduler(|live| {
+            if live.id() == fifo.debug_id() {
+                assert_eq!(live.harts().next().unwrap().name, Some("mgmt"));
+            }
+        });
+    }
+
+    #[test]
+    fn on_leak_test() {
+        std::thread_local! {
+            static LEAKED: core::cell::RefCell<Vec<usize>> = const { core::cell::RefCell::new(Vec::new()) };
+        }
+        let on_leak = |lens: &[usize]| LEAKED.with(|l| l.borrow_mut().extend_from_slice(lens));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_on_leak(on_leak);
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        fifo.pick_next_task_as(0);
+        drop(fifo);
+        assert!(LEAKED.with(|l| l.borrow().is_empty()));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_on_leak(on_leak);
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        drop(fifo);
+        assert_eq!(LEAKED.with(|l| l.borrow().clone()), [0, 1]);
+    }
+
+    #[test]
+    fn should_steal_test() {
+        let mut fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        // only migrate from queues at least 2 long, and never to hart 2
+        fifo.set_should_steal(|thief, _, len| thief != 2 && len >= 2);
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        assert!(fifo.pick_next_task_as(1).is_none());
+        fifo.add_task_on(0, Arc::new(FifoTask::new(2)));
+        assert!(fifo.pick_next_task_as(2).is_none());
+        assert_eq!(*fifo.pick_next_task_as(1).unwrap().inner(), 1);
+        assert_eq!(fifo.queue_len(0), 1);
+    }
+
+    #[test]
+    fn prefetch_work_test() {
+        let mut fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_min_remain(1);
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(1, Arc::new(FifoTask::new(i)));
+            fifo.add_task_on(2, Arc::new(FifoTask::new(i + 3)));
+        }
+        HART.with(|h| h.set(0));
+        assert_eq!(fifo.prefetch_work(3), 3);
+        assert_eq!(fifo.queue_len(0), 3);
+        // every victim keeps at least one task
+        assert_eq!(fifo.prefetch_work(10), 1);
+        assert_eq!((fifo.queue_len(1), fifo.queue_len(2)), (1, 1));
+        fifo.scale_harts(2);
+        HART.with(|h| h.set(2));
+        assert_eq!(fifo.prefetch_work(10), 0);
+    }
+
+    #[test]
+    fn hart_context_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(1));
+        let ctx = fifo.enter();
+        // the id was read on entry
+        HART.with(|h| h.set(0));
+        assert_eq!(ctx.hart_id(), 1);
+        let task = Arc::new(FifoTask::new(1));
+        ctx.add_task(task.clone());
+        ctx.add_task(Arc::new(FifoTask::new(2)));
+        assert_eq!(fifo.queue_len(1), 2);
+        assert!(ctx.remove_task(&task).is_some());
+        let task = ctx.pick_next_task().unwrap();
+        assert_eq!(*task.inner(), 2);
+        assert!(!ctx.task_tick(&task));
+        ctx.put_prev_task(task, false);
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (0, 1));
+    }
+
+    #[test]
+    #[cfg(feature = "lanes")]
+    fn weighted_lanes_test() {
+        use crate::WeightedSmpScheduler;
+        // even tasks go to lane 0, served 3 times for every pick of lane 1
+        let fifo = WeightedSmpScheduler::<1, _, ThreadLock, ThreadHart>::new(
+            &[3, 1],
+            |task: &Arc<FifoTask<usize>>| *task.inner() % 2,
+            scheduler::FifoScheduler::new,
+        );
+        fifo.init();
+        HART.with(|h| h.set(0));
+        for i in 0..10 {
+            fifo.add_task(Arc::new(FifoTask::new(i)));
+        }
+        let order: Vec<_> = core::iter::from_fn(|| fifo.pick_next_task())
+            .map(|task| *task.inner())
+            .collect();
+        // lane 0 runs dry after 5 picks, lane 1 then gets every turn
+        assert_eq!(order, [0, 2, 4, 1, 6, 8, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "rwlock", feature = "fifo"))]
+    fn rw_scheduler_test() {
+        use crate::{RefFifoScheduler, RwSmpScheduler};
+        let queues = (0..2).map(|_| RefFifoScheduler::new()).collect();
+        let rw = RwSmpScheduler::<2, _, spin::RwLock<()>, ThreadHart>::new(queues);
+        rw.init();
+        HART.with(|h| h.set(0));
+        for i in 0..3 {
+            rw.add_task_on(1, Arc::new(FifoTask::new(i)));
+        }
+        let mut seen = Vec::new();
+        rw.for_each_task(1, |task| {
+            // readers share the lock, an exclusive one would deadlock here
+            rw.for_each_task(1, |_| {});
+            seen.push(*task.inner());
+        });
+        assert_eq!(seen, [0, 1, 2]);
+        // a victim being read is passed over
+        rw.with_local(1, |_| assert!(rw.pick_next_task().is_none()));
+        assert_eq!(*rw.pick_next_task().unwrap().inner(), 0);
+        let task = rw.pick_next_task().unwrap();
+        assert!(rw.remove_task(&task).is_none());
+        rw.put_prev_task(task.clone(), true);
+        assert_eq!(rw.snapshot().harts.map(|hart| hart.len), [1, 1]);
+        #[cfg(feature = "metrics")]
+        {
+            let stats = rw.snapshot().harts[0].stats;
+            assert_eq!(
+                (stats.steals, stats.contention, stats.preemptions),
+                (2, 1, 1)
+            );
+        }
+        assert!(rw.remove_task(&task).is_some());
+        assert_eq!(rw.total_len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "rwlock")]
+    fn rw_colliding_keys_test() {
+        use crate::{CFScheduler, RwSmpScheduler};
+        let queues = (0..2).map(|_| CFScheduler::new()).collect();
+        let rw = RwSmpScheduler::<2, _, spin::RwLock<()>, ThreadHart>::new(queues);
+        rw.init();
+        HART.with(|h| h.set(0));
+        // the first task of each hart gets the same (vruntime, id) key
+        let (a, b) = (Arc::new(CFSTask::new(1)), Arc::new(CFSTask::new(2)));
+        rw.add_task_on(0, a.clone());
+        rw.add_task_on(1, b.clone());
+        assert!(Arc::ptr_eq(&rw.remove_task(&b).unwrap(), &b));
+        assert_eq!(rw.snapshot().harts.map(|hart| hart.len), [1, 0]);
+        assert!(Arc::ptr_eq(&rw.pick_next_task().unwrap(), &a));
+    }
+
+    #[test]
+    #[cfg(feature = "rwlock")]
+    #[should_panic(expected = "before init")]
+    fn rw_remove_before_init_test() {
+        use crate::{CFScheduler, RwSmpScheduler};
+        let queues = (0..2).map(|_| CFScheduler::new()).collect();
+        let rw = RwSmpScheduler::<2, _, spin::RwLock<()>, ThreadHart>::new(queues);
+        rw.remove_task(&Arc::new(CFSTask::new(1)));
+    }
+
+    #[test]
+    fn task_id_test() {
+        struct Job(u64);
+        impl crate::TaskId for Job {
+            fn id(&self) -> u64 {
+                self.0
+            }
+        }
+        let fifo = FifoSmpScheduler::<2, Job, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(1, Arc::new(FifoTask::new(Job(i))));
+        }
+        assert_eq!(fifo.contains_task_id(1), Some(1));
+        assert!(fifo.migrate_task_by_id(1, 0));
+        assert_eq!(fifo.contains_task_id(1), Some(0));
+        // a rebuilt handle would not match by address, the id still does
+        assert_eq!(fifo.remove_task_by_id(1).unwrap().inner().0, 1);
+        assert!(fifo.remove_task_by_id(1).is_none());
+        assert!(!fifo.migrate_task_by_id(7, 0));
+        assert_eq!(fifo.hart_queue_snapshot(1), [0, 2]);
+        assert!(fifo.hart_queue_snapshot(0).is_empty());
+        let order: Vec<_> = core::iter::from_fn(|| fifo.pick_next_task_as(1))
+            .map(|task| task.inner().0)
+            .collect();
+        assert_eq!(order, [0, 2]);
+    }
+
+    #[test]
+    fn hart_queue_snapshot_keeps_vruntime_test() {
+        struct Job(u64);
+        impl crate::TaskId for Job {
+            fn id(&self) -> u64 {
+                self.0
+            }
+        }
+        let cfs = CFSSmpScheduler::<2, Job, ThreadLock, ThreadHart>::new();
+        cfs.init();
+        HART.with(|h| h.set(0));
+        cfs.add_task(Arc::new(CFSTask::new(Job(1))));
+        cfs.add_task(Arc::new(CFSTask::new(Job(2))));
+        let task = cfs.pick_next_task().unwrap();
+        for _ in 0..3 {
+            cfs.task_tick(&task);
+        }
+        cfs.put_prev_task(task, false);
+        assert_eq!(cfs.hart_queue_snapshot(0), [2, 1]);
+        assert_eq!(cfs.hart_queue_snapshot(0), [2, 1]);
+        // 1 still has its vruntime of 3 after the snapshots, so a newcomer
+        // at the minimum goes ahead of it
+        cfs.add_task(Arc::new(CFSTask::new(Job(3))));
+        assert_eq!(cfs.hart_queue_snapshot(0), [2, 3, 1]);
+    }
+
+    #[test]
+    fn init_twice_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.init();
+        assert_eq!(fifo.queue_len(1), 1);
+        assert_eq!(*fifo.pick_next_task_as(1).unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn nr_harts_test() {
+        type Sched = FifoSmpScheduler<4, usize, ThreadLock, ThreadHart>;
+        const N: usize = Sched::NR_HARTS;
+        let per_hart = [0usize; N];
+        assert_eq!(per_hart.len(), 4);
+        assert_eq!(
+            crate::LocalScheduler::<scheduler::FifoScheduler<usize>, ThreadLock>::NR_HARTS,
+            1
+        );
+    }
+
+    #[test]
+    fn cooperative_test() {
+        let mut fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        // hart 0 may not steal, so it yields once its queue is empty
+        fifo.set_should_steal(|thief, _, _| thief != 0);
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(0)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(2)));
+        let picks: Vec<_> = core::iter::from_fn(|| fifo.pick_next_task_cooperative())
+            .map(|(hart, task)| (hart, *task.inner()))
+            .collect();
+        // hart 1 yields right after its steal
+        assert_eq!(picks, [(0, 0), (1, 1), (2, 2)]);
+        assert!(fifo.pick_next_task_cooperative().is_none());
+    }
+
+    #[test]
+    fn cooperative_steals_spread_test() {
+        let fifo = FifoSmpScheduler::<4, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(2, Arc::new(FifoTask::new(2)));
+        fifo.add_task_on(3, Arc::new(FifoTask::new(3)));
+        let picks: Vec<_> = core::iter::from_fn(|| fifo.pick_next_task_cooperative())
+            .map(|(hart, task)| (hart, *task.inner()))
+            .collect();
+        // each idle hart steals in turn, from a different victim
+        assert_eq!(picks, [(0, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn enqueue_front_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.enqueue_back(Arc::new(FifoTask::new(1)));
+        fifo.enqueue_back(Arc::new(FifoTask::new(2)));
+        fifo.enqueue_front(Arc::new(FifoTask::new(0)));
+        assert_eq!(fifo.queue_len(0), 3);
+        let order: Vec<_> = core::iter::from_fn(|| fifo.pick_next_task_as(0))
+            .map(|task| *task.inner())
+            .collect();
+        assert_eq!(order, [0, 1, 2]);
+    }
+
+    #[test]
+    fn on_steal_fail_test() {
+        std::thread_local! {
+            static FAILS: core::cell::RefCell<Vec<(usize, usize)>> = const { core::cell::RefCell::new(Vec::new()) };
+        }
+        struct Never;
+        impl crate::StealFilter<Arc<FifoTask<usize>>> for Never {
+            fn can_steal(&self, _: &Arc<FifoTask<usize>>, _: usize, _: usize) -> bool {
+                false
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_on_steal_fail(|hart, probed| FAILS.with(|f| f.borrow_mut().push((hart, probed))));
+        fifo.init();
+        assert!(fifo.pick_next_task_as(0).is_none());
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        assert!(fifo.pick_next_task_as(1).is_some()); // local, no steal phase
+        fifo.add_task_on(1, Arc::new(FifoTask::new(2)));
+        fifo.set_steal_filter(Arc::new(Never));
+        assert!(fifo.pick_next_task_as(2).is_none());
+        assert_eq!(FAILS.with(|f| f.borrow().clone()), [(0, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn with_local_test() {
+        use scheduler::BaseScheduler;
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(1, Arc::new(FifoTask::new(i)));
+        }
+        // rotate the queue by one, atomically
+        fifo.with_local_mut(1, |queue| {
+            let head = queue.pick_next_task().unwrap();
+            queue.add_task(head);
+        });
+        // reading the counters takes no lock, so it is fine in there
+        assert_eq!(fifo.with_local(1, |_| fifo.queue_len(1)), 3);
+        let order: Vec<_> = core::iter::from_fn(|| fifo.pick_next_task_as(1))
+            .map(|task| *task.inner())
+            .collect();
+        assert_eq!(order, [1, 2, 0]);
+    }
+
+    #[test]
+    fn affinity_test() {
+        let task = || Arc::new(FifoTask::new(MetaTask(TaskMeta::new())));
+        let fifo = FifoSmpScheduler::<3, MetaTask, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        let pinned = task();
+        fifo.add_task_with_affinity(pinned.clone(), 0b100);
+        assert_eq!(fifo.queue_len(2), 1);
+        assert_eq!(fifo.affinity_of(&pinned), Some(0b100));
+        assert_eq!(fifo.affinity_of(&task()), None);
+        // hart 0 may not steal it
+        assert!(fifo.pick_next_task_tracked().is_none());
+        // no online hart in the mask
+        assert_eq!(fifo.update_affinity(&pinned, 0b1000), Err(0b1000));
+        assert_eq!(fifo.affinity_of(&pinned), Some(0b100));
+        // hart 2 is no longer allowed, so the task moves to hart 0
+        assert_eq!(fifo.update_affinity(&pinned, 0b011), Ok(true));
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(2)), (1, 0));
+        assert_eq!(fifo.update_affinity(&pinned, 0b001), Ok(false));
+        let picked = fifo.pick_next_task_tracked().unwrap();
+        assert!(Arc::ptr_eq(&picked, &pinned));
+    }
+
+    #[test]
+    fn affinity_colliding_keys_test() {
+        let task = || Arc::new(CFSTask::new(MetaTask(TaskMeta::new())));
+        let cfs = CFSSmpScheduler::<3, MetaTask, ThreadLock, ThreadHart>::new();
+        cfs.init();
+        HART.with(|h| h.set(0));
+        // the first task of each hart gets the same (vruntime, id) key
+        let (a, b) = (task(), task());
+        cfs.add_task_on(0, a.clone());
+        cfs.add_task_on(1, b.clone());
+        assert_eq!(cfs.update_affinity(&b, 0b100), Ok(true));
+        assert_eq!(cfs.contains_task(&a), Some(0));
+        assert_eq!(cfs.contains_task(&b), Some(2));
+        // already on an allowed hart
+        assert_eq!(cfs.update_affinity(&a, 0b011), Ok(false));
+        assert_eq!([0, 1, 2].map(|i| cfs.queue_len(i)), [1, 0, 1]);
+    }
+
+    #[test]
+    fn pinned_tracked_test() {
+        let task = || Arc::new(FifoTask::new(MetaTask(TaskMeta::new())));
+        let fifo = FifoSmpScheduler::<2, MetaTask, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        let pinned = task();
+        fifo.add_task_pinned(pinned.clone());
+        assert!(!pinned.task_meta().is_migratable());
+        assert!(task().task_meta().is_migratable());
+        HART.with(|h| h.set(1));
+        assert!(fifo.pick_next_task_tracked().is_none());
+        pinned.task_meta().set_migratable(true);
+        let stolen = fifo.pick_next_task_tracked().unwrap();
+        assert!(Arc::ptr_eq(&stolen, &pinned));
+    }
+
+    #[test]
+    fn pinned_untracked_test() {
+        let task = || Arc::new(FifoTask::new(MetaTask(TaskMeta::new())));
+        let fifo = FifoSmpScheduler::<2, MetaTask, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        let pinned = task();
+        fifo.add_task_pinned(pinned.clone());
+        for _ in 0..3 {
+            fifo.add_task(task());
+        }
+        // the plain paths pass over the pinned head too
+        assert_eq!(fifo.migrate_half_to_idle(), 2);
+        assert_eq!(fifo.queue_len(0), 2);
+        assert!(fifo.pick_next_task_as(1).is_some());
+        assert!(fifo.pick_next_task_as(1).is_some());
+        assert!(fifo.pick_next_task_as(1).is_some());
+        assert!(fifo.pick_next_task_as(1).is_none());
+        HART.with(|h| h.set(1));
+        assert!(fifo.steal_from_longest().is_none());
+        assert_eq!(
+            fifo.try_steal_once(0).err(),
+            Some(crate::StealMiss::Refused)
+        );
+        HART.with(|h| h.set(0));
+        let picked = fifo.pick_next_task().unwrap();
+        assert!(Arc::ptr_eq(&picked, &pinned));
+    }
+
+    #[test]
+    fn task_class_test() {
+        struct Hart3;
+        impl ScheduleHart for Hart3 {
+            fn hart_id() -> usize {
+                3
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<4, MetaTask, spin::Mutex<()>, Hart3>::new();
+        fifo.set_task_classes(alloc::vec![0b0011, 0b1100]);
+        fifo.init();
+        fifo.add_task_on(2, Arc::new(FifoTask::new(MetaTask(TaskMeta::new()))));
+        fifo.add_task_classed(0, Arc::new(FifoTask::new(MetaTask(TaskMeta::new()))));
+        assert_eq!(fifo.queue_len(0), 1); // hart 3 is not in class 0
+        assert!(fifo.pick_next_task_tracked().is_some()); // unclassed, from hart 2
+        assert!(fifo.pick_next_task_tracked().is_none()); // class 0 stays on harts 0-1
+        assert_eq!(fifo.queue_len(0), 1);
+    }
+
+    #[derive(Clone)]
+    struct VecScheduler(alloc::collections::VecDeque<Arc<usize>>);
+
+    impl scheduler::BaseScheduler for VecScheduler {
+        type SchedItem = Arc<usize>;
+        fn init(&mut self) {}
+        fn add_task(&mut self, task: Self::SchedItem) {
+            self.0.push_back(task);
+        }
+        fn remove_task(&mut self, task: &Self::SchedItem) -> Option<Self::SchedItem> {
+            let idx = self.0.iter().position(|t| Arc::ptr_eq(t, task))?;
+            self.0.remove(idx)
+        }
+        fn pick_next_task(&mut self) -> Option<Self::SchedItem> {
+            self.0.pop_front()
+        }
+        fn put_prev_task(&mut self, prev: Self::SchedItem, _preempt: bool) {
+            self.0.push_back(prev);
+        }
+        fn task_tick(&mut self, _current: &Self::SchedItem) -> bool {
+            false
+        }
+        fn set_priority(&mut self, _task: &Self::SchedItem, _prio: isize) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn clone_test() {
+        let queues = alloc::vec![
+            VecScheduler(Default::default()),
+            VecScheduler(Default::default())
+        ];
+        let sched = SmpScheduler::<2, _, spin::Mutex<()>, ScheduleHartImpl>::new(queues);
+        sched.init();
+        sched.add_task_on(0, Arc::new(1));
+        let copy = sched.clone();
+        assert_eq!(sched.pick_next_task_as(0).as_deref(), Some(&1));
+        assert!(sched.pick_next_task_as(0).is_none());
+        assert_eq!(copy.pick_next_task_as(0).as_deref(), Some(&1));
+        #[cfg(feature = "metrics")]
+        assert_eq!(copy.stats(0).picks, 1);
+    }
+
+    #[test]
+    fn rc_fifo_test() {
+        use crate::RefFifoSmpScheduler;
+        use alloc::rc::Rc;
+        struct SingleHart;
+        impl ScheduleHart for SingleHart {
+            fn hart_id() -> usize {
+                0
+            }
+        }
+        type RcFifo =
+            RefFifoSmpScheduler<1, usize, spin::Mutex<()>, SingleHart, Rc<FifoTask<usize>>>;
+        let fifo = RcFifo::new();
+        fifo.init();
+        let task = Rc::new(FifoTask::new(1));
+        fifo.add_task(task.clone());
+        fifo.add_task(Rc::new(FifoTask::new(2)));
+        assert!(fifo.remove_task(&task).is_some());
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 2);
+    }
+
+    std::thread_local!(static HART: core::cell::Cell<usize> = const { core::cell::Cell::new(0) });
+    type ThreadLock = spin::Mutex<()>;
+    struct ThreadHart;
+    impl ScheduleHart for ThreadHart {
+        fn hart_id() -> usize {
+            HART.with(|h| h.get())
+        }
+    }
+
+    /// A task carrying the bookkeeping of the tracked paths.
+    struct MetaTask(TaskMeta);
+    impl HasTaskMeta for MetaTask {
+        fn task_meta(&self) -> &TaskMeta {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn steal_race_stress_test() {
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+        const HARTS: usize = 4;
+        const TASKS: usize = 2000;
+        let fifo = Arc::new(FifoSmpScheduler::<HARTS, usize, spin::Mutex<()>, ThreadHart>::new());
+        fifo.init();
+        let picked = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let mut threads = std::vec::Vec::new();
+        for hart in 0..HARTS {
+            let (fifo, picked, done) = (fifo.clone(), picked.clone(), done.clone());
+            threads.push(thread::spawn(move || {
+                HART.with(|h| h.set(hart));
+                let mut produced = 0;
+                loop {
+                    // harts 0 and 1 produce while everybody consumes
+                    if hart < 2 && produced < TASKS {
+                        fifo.add_task(Arc::new(FifoTask::new(hart)));
+                        produced += 1;
+                    }
+                    if fifo.pick_next_task().is_some() {
+                        picked.fetch_add(1, Ordering::SeqCst);
+                    } else if done.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if picked.load(Ordering::SeqCst) == 2 * TASKS {
+                        done.store(true, Ordering::SeqCst);
+                    }
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(picked.load(Ordering::SeqCst), 2 * TASKS);
+        assert!((0..HARTS).all(|hart| fifo.queue_len(hart) == 0));
+    }
+
+    #[test]
+    fn fifo_no_starvation_test() {
+        let fifo = FifoSmpScheduler::<2, usize, spin::Mutex<()>, ScheduleHartImpl>::new();
+        fifo.init();
+        let mut next = 0;
+        let mut picked = std::vec::Vec::new();
+        // hart 0 keeps producing while both harts take turns picking
+        for round in 0..100 {
+            for _ in 0..2 {
+                fifo.add_task_on(0, Arc::new(FifoTask::new(next)));
+                next += 1;
+            }
+            picked.push(*fifo.pick_next_task_as(round % 2).unwrap().inner());
+        }
+        while let Some(task) = fifo.pick_next_task_as(1) {
+            picked.push(*task.inner());
+        }
+        // nobody jumped the queue, so every task was picked, oldest first
+        assert_eq!(picked, (0..next).collect::<std::vec::Vec<_>>());
+    }
+
+    #[test]
+    fn scale_harts_test() {
+        let fifo = FifoSmpScheduler::<4, usize, spin::Mutex<()>, ScheduleHartImpl>::new();
+        fifo.init();
+        for hart in 0..4 {
+            fifo.add_task_on(hart, Arc::new(FifoTask::new(hart)));
+        }
+        assert_eq!(fifo.scale_harts(2), 2);
+        assert_eq!(fifo.online_harts(), 2);
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (2, 2));
+        assert_eq!(fifo.queue_len(2) + fifo.queue_len(3), 0);
+        assert_eq!(fifo.scale_harts(4), 0);
+        assert_eq!(fifo.online_harts(), 4);
+    }
+
+    #[test]
+    fn scale_harts_side_queues_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let mut fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_run_next_slots((0..3).map(|_| crate::FifoScheduler::new()).collect());
+        #[cfg(feature = "urgent")]
+        fifo.set_urgent_lanes((0..3).map(|_| crate::FifoScheduler::new()).collect());
+        fifo.init();
+        HART.with(|h| h.set(2));
+        fifo.add_task_run_next(task(1));
+        #[cfg(feature = "urgent")]
+        fifo.add_urgent_task(task(2));
+        fifo.add_task(task(3));
+        let side = if cfg!(feature = "urgent") { 1 } else { 0 };
+        assert_eq!(fifo.scale_harts(2), 2 + side);
+        assert_eq!(fifo.run_next_len(2) + fifo.queue_len(2), 0);
+        #[cfg(feature = "urgent")]
+        assert_eq!((fifo.urgent_len(2), fifo.urgent_len(0)), (0, 1));
+        // the slot moved to hart 0, the least loaded
+        assert_eq!(fifo.run_next_len(0), 1);
+        HART.with(|h| h.set(0));
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn overflow_policy_test() {
+        use crate::OverflowPolicy;
+        let task = |v| Arc::new(FifoTask::new(v));
+        let new = |policy| {
+            let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+            fifo.set_capacity(Some(1), policy);
+            fifo.init();
+            assert!(fifo.try_add_task(task(1)).is_ok());
+            fifo
+        };
+        let fifo = new(OverflowPolicy::Reject);
+        assert_eq!(fifo.try_add_task(task(2)).map_err(|t| *t.inner()), Err(2));
+        let fifo = new(OverflowPolicy::DropNewest);
+        assert!(fifo.try_add_task(task(2)).is_ok());
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 1);
+        let fifo = new(OverflowPolicy::DropOldest);
+        assert!(fifo.try_add_task(task(2)).is_ok());
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 2);
+        assert!(fifo.pick_next_task_as(0).is_none());
+        let fifo = new(OverflowPolicy::Spill);
+        assert!(fifo.try_add_task(task(2)).is_ok());
+        assert_eq!(fifo.queue_len(1), 1);
+        assert!(fifo.try_add_task(task(3)).is_err());
+    }
+
+    #[test]
+    fn hart_load_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        assert_eq!(fifo.hart_load(0), 0.0);
+        HART.with(|h| h.set(0));
+        let current = task(0);
+        fifo.add_task(task(1));
+        for _ in 0..64 {
+            fifo.task_tick(&current);
+        }
+        // the average has converged on one queued task
+        assert!(fifo.hart_load_fixed(0) > 240 && fifo.hart_load_fixed(0) <= 256);
+        assert!((fifo.hart_load(0) - 0.5).abs() < 0.02);
+        assert_eq!(fifo.hart_load(1), 0.0);
+        fifo.set_capacity(Some(4), crate::OverflowPolicy::Reject);
+        assert_eq!(fifo.hart_load(0), 0.25);
+    }
+
+    #[test]
+    #[should_panic(expected = "call init() first")]
+    fn uninitialized_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+    }
+
+    #[test]
+    fn task_tick_all_test() {
+        let rr = RRSmpScheduler::<2, 2, usize, ThreadLock, ThreadHart>::new();
+        rr.init();
+        let current = [Some(Arc::new(RRTask::new(0))), None];
+        assert_eq!(rr.task_tick_all(&current), [false, false]);
+        // the second tick uses up the time slice
+        assert_eq!(rr.task_tick_all(&current), [true, false]);
+    }
+
+    #[test]
+    fn steal_filter_test() {
+        struct Odd;
+        impl crate::StealFilter<Arc<FifoTask<usize>>> for Odd {
+            fn can_steal(&self, item: &Arc<FifoTask<usize>>, from: usize, to: usize) -> bool {
+                assert_ne!(from, to);
+                *item.inner() % 2 == 1
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_steal_filter(Arc::new(Odd));
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(2)));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(3)));
+        // hart 0's head is pinned, so the thief skips to hart 1
+        assert_eq!(*fifo.pick_next_task_as(2).unwrap().inner(), 3);
+        assert!(fifo.pick_next_task_as(2).is_none());
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 2);
+    }
+
+    #[test]
+    fn register_hart_test() {
+        use crate::{HartSlot, RegisterHart};
+        use core::sync::atomic::AtomicUsize;
+        struct Slot;
+        impl HartSlot for Slot {
+            fn slot() -> &'static AtomicUsize {
+                static SLOT: AtomicUsize = AtomicUsize::new(0);
+                &SLOT
+            }
+        }
+        type Hart = RegisterHart<Slot>;
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, Hart>::new();
+        fifo.init();
+        Hart::register(1);
+        assert_eq!(Hart::hart_id(), 1);
+        fifo.add_task(Arc::new(FifoTask::new(7)));
+        assert_eq!(fifo.queue_len(1), 1);
+    }
+
+    #[test]
+    fn steal_boost_test() {
+        static BOOSTED: AtomicUsize = AtomicUsize::new(0);
+        let mut cfs = CFSSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        cfs.set_steal_boost(|task: &Arc<CFSTask<usize>>, from, to| {
+            assert_eq!((*task.inner(), from, to), (2, 1, 0));
+            BOOSTED.fetch_add(1, Ordering::Relaxed);
+            Some(-5)
+        });
+        cfs.init();
+        cfs.add_task_on(0, Arc::new(CFSTask::new(1)));
+        cfs.add_task_on(1, Arc::new(CFSTask::new(2)));
+        assert_eq!(*cfs.pick_next_task_as(0).unwrap().inner(), 1);
+        assert_eq!(BOOSTED.load(Ordering::Relaxed), 0);
+        assert_eq!(*cfs.pick_next_task_as(0).unwrap().inner(), 2);
+        assert_eq!(BOOSTED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn reset_stats_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(2)));
+        assert!(fifo.pick_next_task().is_some());
+        assert!(fifo.pick_next_task().is_some()); // stolen from hart 1
+        assert_eq!(fifo.queue_len(1), 0);
+        assert_eq!(fifo.stats(0).picks, 2);
+        assert_eq!(fifo.total_enqueued(1), 1);
+        assert_eq!(fifo.total_dequeued(1), 1);
+        assert_eq!(fifo.stats(0).steals, 1);
+        fifo.reset_stats();
+        assert_eq!(fifo.stats(0), Default::default());
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn watermark_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        fifo.pick_next_task_as(0);
+        assert_eq!(fifo.watermark(0), (0, 3));
+        fifo.reset_stats();
+        assert_eq!(fifo.watermark(0), (2, 2));
+        fifo.pick_next_task_as(1); // stolen
+        fifo.add_task_on(0, Arc::new(FifoTask::new(3)));
+        assert_eq!(fifo.watermark(0), (1, 2));
+        assert_eq!(fifo.watermark(1), (0, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn preempt_count_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(1));
+        fifo.put_prev_task(Arc::new(FifoTask::new(1)), true);
+        fifo.put_prev_task(Arc::new(FifoTask::new(2)), false);
+        fifo.enter().put_prev_task(Arc::new(FifoTask::new(3)), true);
+        assert_eq!((fifo.preempt_count(0), fifo.preempt_count(1)), (0, 2));
+        fifo.reset_stats();
+        assert_eq!(fifo.preempt_count(1), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn total_stats_test() {
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        for hart in 0..3 {
+            assert!(fifo.pick_next_task_as(hart).is_some());
+        }
+        assert_eq!(fifo.total_picks(), 3);
+        assert_eq!(fifo.total_steals(), 2);
+        assert_eq!(fifo.last_steal_victim(0), None);
+        assert_eq!(fifo.last_steal_victim(2), Some(0));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(3)));
+        fifo.scale_harts(2);
+        let snapshot = fifo.snapshot();
+        assert_eq!(snapshot.total, 1);
+        assert_eq!(snapshot.harts[1].len, 1);
+        assert!(!snapshot.harts[2].online);
+        assert_eq!(snapshot.harts[2].stats.steals, 1);
+    }
+
+    #[test]
+    fn fairness_report_test() {
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        assert_eq!(fifo.imbalance(), 0);
+        for i in 0..4 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        fifo.add_task_on(2, Arc::new(FifoTask::new(4)));
+        let report = fifo.fairness_report();
+        assert_eq!((report.online, report.total, report.mean()), (3, 5, 1));
+        assert_eq!((report.min, report.max), ((0, 1), (4, 0)));
+        // |12 - 5| + |0 - 5| + |3 - 5| over 3 harts
+        assert_eq!(report.deviation, 4);
+        assert_eq!(fifo.imbalance(), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "urgent")]
+    fn urgent_lane_test() {
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_urgent_lanes(alloc::vec![
+            crate::FifoScheduler::new(),
+            crate::FifoScheduler::new()
+        ]);
+        fifo.init();
+        let task = |v| Arc::new(FifoTask::new(v));
+        fifo.add_task_on(0, task(1));
+        fifo.add_urgent_task_on(0, task(2));
+        fifo.add_task_on(1, task(3));
+        fifo.add_urgent_task_on(1, task(4));
+        assert_eq!(fifo.urgent_len(0), 1);
+        assert_eq!(fifo.queue_len(0), 1);
+        let picked: Vec<_> = (0..4)
+            .map(|_| *fifo.pick_next_task_as(0).unwrap().inner())
+            .collect();
+        // own urgent lane, own queue, then the victim's urgent lane first
+        assert_eq!(picked, [2, 1, 4, 3]);
+    }
+
+    #[test]
+    fn migrate_half_to_idle_test() {
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        for i in 0..7 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        fifo.add_task_on(1, Arc::new(FifoTask::new(7)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(8)));
+        assert_eq!(fifo.migrate_half_to_idle(), 3);
+        assert_eq!(fifo.queue_len(0), 4);
+        assert_eq!(fifo.queue_len(1) + fifo.queue_len(2), 5);
+        // 4 against 1 on the other idle hart
+        assert_eq!(fifo.migrate_half_to_idle(), 1);
+        // 3 against 2 is close enough
+        assert_eq!(fifo.migrate_half_to_idle(), 0);
+    }
+
+    #[test]
+    fn put_prev_spill_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_capacity(Some(1), crate::OverflowPolicy::Reject);
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task(task(1));
+        fifo.put_prev_task(task(2), true);
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (1, 1));
+        // everyone is full, so the task stays local over capacity
+        fifo.put_prev_task(task(3), true);
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (2, 1));
+        fifo.balanced_add(task(4));
+        assert_eq!(fifo.queue_len(1), 2);
+    }
+
+    #[test]
+    fn put_prev_overflow_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_capacity(Some(1), crate::OverflowPolicy::Reject);
+        fifo.set_overflow_queue(crate::FifoScheduler::new());
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task(task(1));
+        // preempted under pressure, the task goes to the overflow queue
+        // rather than spilling to hart 1
+        fifo.put_prev_task(task(2), true);
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (1, 0));
+        assert_eq!(fifo.overflow_len(), 1);
+        // the local queue comes first
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 1);
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 2);
+        assert_eq!(fifo.overflow_len(), 0);
+        // below capacity nothing overflows
+        fifo.put_prev_task(task(3), true);
+        assert_eq!((fifo.queue_len(0), fifo.overflow_len()), (1, 0));
+    }
+
+    #[test]
+    fn try_steal_once_test() {
+        use crate::{StealFilter, StealMiss};
+        struct Odd;
+        impl StealFilter<Arc<FifoTask<usize>>> for Odd {
+            fn can_steal(&self, task: &Arc<FifoTask<usize>>, _from: usize, _to: usize) -> bool {
+                task.inner() % 2 == 1
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_steal_filter(Arc::new(Odd));
+        fifo.init();
+        HART.with(|h| h.set(0));
+        assert_eq!(fifo.try_steal_once(1).err(), Some(StealMiss::Empty));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(2)));
+        assert_eq!(*fifo.try_steal_once(1).unwrap().inner(), 1);
+        assert_eq!(fifo.try_steal_once(1).err(), Some(StealMiss::Refused));
+        assert_eq!(fifo.queue_len(1), 1);
+        fifo.with_local(1, |_| {
+            assert_eq!(fifo.try_steal_once(1).err(), Some(StealMiss::Contended));
+        });
+    }
+
+    #[test]
+    fn steal_filter_keeps_order_test() {
+        use crate::{StealEnd, StealFilter, StealMiss};
+        struct Odd;
+        impl StealFilter<Arc<FifoTask<usize>>> for Odd {
+            fn can_steal(&self, task: &Arc<FifoTask<usize>>, _from: usize, _to: usize) -> bool {
+                task.inner() % 2 == 1
+            }
+        }
+        for end in [StealEnd::Head, StealEnd::Tail] {
+            let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+            fifo.set_steal_filter(Arc::new(Odd));
+            fifo.set_steal_end(end);
+            fifo.init();
+            HART.with(|h| h.set(0));
+            for i in [2, 1, 4, 3, 6] {
+                fifo.add_task_on(1, Arc::new(FifoTask::new(i)));
+            }
+            // the thief gets the first odd task from its end, past the even
+            // ones, which stay where they were
+            let first = if end == StealEnd::Head { 1 } else { 3 };
+            assert_eq!(*fifo.pick_next_task().unwrap().inner(), first);
+            assert_eq!(*fifo.pick_next_task().unwrap().inner(), 4 - first);
+            assert_eq!(fifo.try_steal_once(1).err(), Some(StealMiss::Refused));
+            let order: Vec<usize> = core::iter::from_fn(|| fifo.pick_next_task_as(1))
+                .map(|task| *task.inner())
+                .collect();
+            assert_eq!(order, [2, 4, 6]);
+        }
+    }
+
+    #[test]
+    fn steal_filter_keeps_rr_order_test() {
+        use crate::StealFilter;
+        struct Odd;
+        impl StealFilter<Arc<RRTask<usize, 2>>> for Odd {
+            fn can_steal(&self, task: &Arc<RRTask<usize, 2>>, _from: usize, _to: usize) -> bool {
+                task.inner() % 2 == 1
+            }
+        }
+        let mut rr = RRSmpScheduler::<2, 2, usize, ThreadLock, ThreadHart>::new();
+        rr.set_steal_filter(Arc::new(Odd));
+        rr.init();
+        HART.with(|h| h.set(0));
+        for i in [2, 4, 1, 6] {
+            rr.add_task_on(1, Arc::new(RRTask::new(i)));
+        }
+        assert_eq!(*rr.pick_next_task().unwrap().inner(), 1);
+        assert!(rr.pick_next_task().is_none());
+        let order: Vec<usize> = core::iter::from_fn(|| rr.pick_next_task_as(1))
+            .map(|task| *task.inner())
+            .collect();
+        assert_eq!(order, [2, 4, 6]);
+    }
+
+    #[test]
+    fn cross_node_steal_test() {
+        use core::cell::RefCell;
+        std::thread_local! {
+            static CROSSED: RefCell<Vec<(usize, usize, usize)>> = const { RefCell::new(Vec::new()) };
+        }
+        let mut fifo = FifoSmpScheduler::<4, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_on_cross_node_steal([0, 0, 1, 1], |task, from, to| {
+            CROSSED.with(|c| c.borrow_mut().push((*task.inner(), from, to)));
+        });
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(3, Arc::new(FifoTask::new(2)));
+        fifo.pick_next_task_as(0); // from hart 1, same node
+        fifo.pick_next_task_as(0); // from hart 3, across nodes
+        CROSSED.with(|c| assert_eq!(*c.borrow(), [(2, 3, 0)]));
+    }
+
+    #[test]
+    fn weighted_fair_test() {
+        let cfs = CFSSmpScheduler::<3, (usize, isize), ThreadLock, ThreadHart>::new();
+        cfs.init();
+        cfs.add_task_on(0, Arc::new(CFSTask::new((1, 10))));
+        cfs.add_task_on(0, Arc::new(CFSTask::new((2, 30))));
+        cfs.add_task_on(2, Arc::new(CFSTask::new((3, 20))));
+        let vruntime = |task: &Arc<CFSTask<(usize, isize)>>| task.inner().1;
+        HART.with(|h| h.set(0));
+        assert_eq!(
+            cfs.pick_next_task_weighted_fair(vruntime)
+                .unwrap()
+                .inner()
+                .0,
+            1
+        );
+        // hart 2 holds the most deserving task now
+        assert_eq!(
+            cfs.pick_next_task_weighted_fair(vruntime)
+                .unwrap()
+                .inner()
+                .0,
+            3
+        );
+        assert_eq!(cfs.queue_len(2), 0);
+        assert_eq!(
+            cfs.pick_next_task_weighted_fair(vruntime)
+                .unwrap()
+                .inner()
+                .0,
+            2
+        );
+        assert!(cfs.pick_next_task_weighted_fair(vruntime).is_none());
+    }
+
+    #[test]
+    fn weighted_fair_gates_test() {
+        let task = |v| Arc::new(CFSTask::new(v));
+        let mut cfs = CFSSmpScheduler::<2, (usize, isize), ThreadLock, ThreadHart>::new();
+        cfs.set_run_next_slots((0..2).map(|_| crate::CFScheduler::new()).collect());
+        #[cfg(feature = "urgent")]
+        cfs.set_urgent_lanes((0..2).map(|_| crate::CFScheduler::new()).collect());
+        cfs.init();
+        let vruntime = |task: &Arc<CFSTask<(usize, isize)>>| task.inner().1;
+        HART.with(|h| h.set(0));
+        cfs.add_task_on(1, task((1, 10)));
+        cfs.add_task_run_next(task((2, 30)));
+        #[cfg(feature = "urgent")]
+        cfs.add_urgent_task(task((3, 20)));
+        cfs.replenish_budget(0, 0);
+        assert!(cfs.pick_next_task_weighted_fair(vruntime).is_none());
+        cfs.replenish_budget(0, usize::MAX);
+        let pick = || {
+            cfs.pick_next_task_weighted_fair(vruntime)
+                .unwrap()
+                .inner()
+                .0
+        };
+        assert_eq!(pick(), 2);
+        #[cfg(feature = "urgent")]
+        assert_eq!(pick(), 3);
+        assert_eq!(pick(), 1);
+    }
+
+    #[test]
+    fn steal_spin_budget_test() {
+        use core::sync::atomic::AtomicBool;
+        let mut fifo = FifoSmpScheduler::<2, usize, spin::Mutex<()>, ThreadHart>::new();
+        fifo.set_steal_spin_budget(1 << 30);
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        let fifo = Arc::new(fifo);
+        let locked = Arc::new(AtomicBool::new(false));
+        let owner = {
+            let (fifo, locked) = (fifo.clone(), locked.clone());
+            std::thread::spawn(move || {
+                fifo.with_local(1, |_| {
+                    locked.store(true, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                });
+            })
+        };
+        while !locked.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        HART.with(|h| h.set(0));
+        // spins through the owner's critical section instead of giving up
+        assert_eq!(*fifo.try_steal_once(1).unwrap().inner(), 1);
+        owner.join().unwrap();
+    }
+
+    #[test]
+    fn should_preempt_current_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        let current = Arc::new(FifoTask::new(5));
+        // lower values rank higher
+        let outranks = |next: &Arc<FifoTask<usize>>, current: &Arc<FifoTask<usize>>| {
+            next.inner() < current.inner()
+        };
+        assert!(!fifo.should_preempt_current(&current, outranks));
+        fifo.add_task(Arc::new(FifoTask::new(7)));
+        fifo.add_task(Arc::new(FifoTask::new(1)));
+        // only the head counts
+        assert!(!fifo.should_preempt_current(&current, outranks));
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 7);
+        assert!(fifo.should_preempt_current(&current, outranks));
+        assert_eq!(fifo.queue_len(0), 1);
+    }
+
+    #[test]
+    fn should_preempt_current_keeps_vruntime_test() {
+        let cfs = CFSSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        cfs.init();
+        HART.with(|h| h.set(0));
+        cfs.add_task(Arc::new(CFSTask::new(1)));
+        cfs.add_task(Arc::new(CFSTask::new(2)));
+        // run 1 then 2 for a while, so they queue at vruntimes 3 and 5
+        for ticks in [3, 5] {
+            let task = cfs.pick_next_task().unwrap();
+            for _ in 0..ticks {
+                cfs.task_tick(&task);
+            }
+            cfs.put_prev_task(task, false);
+        }
+        // a heavily weighted current does not age past the queue minimum in
+        // a tick, so the head has to be looked at
+        let current = Arc::new(CFSTask::new(0));
+        assert!(cfs.set_priority(&current, -20));
+        assert!(!cfs.should_preempt_current(&current, |_, _| false));
+        // a newcomer starts at the minimum, ahead of 1 and 2 which kept theirs
+        cfs.add_task(Arc::new(CFSTask::new(4)));
+        let order: Vec<usize> = (0..3)
+            .map(|_| *cfs.pick_next_task().unwrap().inner())
+            .collect();
+        assert_eq!(order, [4, 1, 2]);
+    }
+
+    #[test]
+    fn drain_each_test() {
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_capacity(Some(1), crate::OverflowPolicy::Reject);
+        fifo.set_overflow_queue(crate::FifoScheduler::new());
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(2)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        fifo.put_prev_task(Arc::new(FifoTask::new(3)), false); // overflows
+        let mut seen = Vec::new();
+        fifo.drain_each(|task| seen.push(*task.inner()));
+        assert_eq!(seen, [1, 2, 3]);
+        assert_eq!(
+            (fifo.queue_len(0), fifo.queue_len(1), fifo.overflow_len()),
+            (0, 0, 0)
+        );
+        fifo.add_task_on(1, Arc::new(FifoTask::new(4)));
+        assert_eq!(fifo.drain().len(), 1);
+        assert!(fifo.pick_next_task().is_none());
+    }
+
+    #[test]
+    fn task_sink_test() {
+        thread_local! {
+            static RECYCLED: core::cell::RefCell<Vec<usize>> = const { core::cell::RefCell::new(Vec::new()) };
+        }
+        struct Pool;
+        impl crate::TaskSink<Arc<FifoTask<usize>>> for Pool {
+            fn recycle(&self, task: Arc<FifoTask<usize>>) {
+                RECYCLED.with(|r| r.borrow_mut().push(*task.inner()));
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_capacity(Some(1), crate::OverflowPolicy::DropOldest);
+        fifo.set_task_sink(Arc::new(Pool));
+        fifo.init();
+        HART.with(|h| h.set(0));
+        assert!(fifo.try_add_task(Arc::new(FifoTask::new(1))).is_ok());
+        assert!(fifo.try_add_task(Arc::new(FifoTask::new(2))).is_ok()); // drops 1
+        fifo.add_task_on(1, Arc::new(FifoTask::new(3)));
+        fifo.clear();
+        assert_eq!(RECYCLED.with(|r| r.take()), [1, 2, 3]);
+        fifo.add_task_on(0, Arc::new(FifoTask::new(5)));
+        assert_eq!(fifo.reinit(0), 1);
+        assert_eq!(RECYCLED.with(|r| r.take()), [5]);
+        fifo.add_task_on(1, Arc::new(FifoTask::new(4)));
+        drop(fifo);
+        assert_eq!(RECYCLED.with(|r| r.take()), [4]);
+    }
+
+    #[test]
+    fn same_default_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        let queued = Arc::new(FifoTask::new(1));
+        fifo.add_task_on(1, queued.clone());
+        // without a predicate handles match by address
+        assert_eq!(fifo.contains_task(&queued), Some(1));
+        assert_eq!(fifo.contains_task(&Arc::new(FifoTask::new(1))), None);
+        assert_eq!(fifo.queue_len(1), 1);
+    }
+
+    #[test]
+    fn same_colliding_keys_test() {
+        let cfs = CFSSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        cfs.init();
+        HART.with(|h| h.set(0));
+        // the first task of each hart gets the same (vruntime, id) key
+        let (a, b) = (Arc::new(CFSTask::new(1)), Arc::new(CFSTask::new(2)));
+        cfs.add_task_on(0, a.clone());
+        cfs.add_task_on(1, b.clone());
+        assert_eq!(cfs.contains_task(&a), Some(0));
+        assert_eq!(cfs.contains_task(&b), Some(1));
+        assert!(cfs.migrate_task(&b, 2));
+        assert_eq!(cfs.contains_task(&a), Some(0));
+        assert_eq!(cfs.contains_task(&b), Some(2));
+        assert_eq!([0, 1, 2].map(|i| cfs.queue_len(i)), [1, 0, 1]);
+        // the local hart has a task under b's key, but not b itself
+        assert!(cfs.remove_task(&b).is_none());
+        assert_eq!(*cfs.pick_next_task_as(0).unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn remove_tasks_colliding_keys_test() {
+        let cfs = CFSSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        cfs.init();
+        let task = |v| Arc::new(CFSTask::new(v));
+        let (a, b, c) = (task(1), task(2), task(3));
+        // a and b share the key (0, 0), c and the second task of hart 0 the
+        // key (0, 1)
+        cfs.add_task_on(0, a.clone());
+        cfs.add_task_on(0, task(4));
+        cfs.add_task_on(1, b.clone());
+        cfs.add_task_on(1, c.clone());
+        assert_eq!(cfs.remove_tasks(&[b, c, task(5)]), 2);
+        assert_eq!([cfs.queue_len(0), cfs.queue_len(1)], [2, 0]);
+        assert_eq!(cfs.contains_task(&a), Some(0));
+        let order: Vec<usize> = core::iter::from_fn(|| cfs.pick_next_task_as(0))
+            .map(|task| *task.inner())
+            .collect();
+        assert_eq!(order, [1, 4]);
+    }
+
+    #[test]
+    fn same_predicate_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new_with_same(|a, b| {
+            a.inner() == b.inner()
+        });
+        fifo.init();
+        HART.with(|h| h.set(0));
+        for i in 0..3 {
+            fifo.add_task_on(1, task(i));
+        }
+        // rebuilt handles match by value rather than by address
+        assert_eq!(fifo.contains_task(&task(1)), Some(1));
+        assert!(fifo.migrate_task(&task(1), 0));
+        assert_eq!(fifo.contains_task(&task(1)), Some(0));
+        assert_eq!(*fifo.remove_task(&task(1)).unwrap().inner(), 1);
+        assert_eq!(fifo.remove_tasks(&[task(0), task(7)]), 1);
+        assert_eq!(fifo.contains_task(&task(2)), Some(1));
+        assert!(!fifo.migrate_task(&task(1), 1));
+    }
+
+    #[test]
+    fn budget_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        for i in 0..3 {
+            fifo.add_task(Arc::new(FifoTask::new(i)));
+        }
+        assert_eq!(fifo.budget_remaining(0), usize::MAX);
+        fifo.replenish_budget(0, 10);
+        assert!(fifo.pick_next_task().is_some());
+        fifo.charge_budget(0, 15);
+        assert_eq!(fifo.budget_remaining(0), 0);
+        assert!(fifo.pick_next_task().is_none());
+        // other harts keep their own budget
+        assert!(fifo.pick_next_task_as(1).is_some());
+        fifo.replenish_budget(0, 1);
+        assert!(fifo.pick_next_task().is_some());
+    }
+
+    #[test]
+    fn producer_affinity_test() {
+        let victims = |affinity| {
+            let mut fifo = FifoSmpScheduler::<4, usize, ThreadLock, ThreadHart>::new();
+            fifo.set_producer_affinity(affinity);
+            fifo.init();
+            // hart 1 is the producer, hart 3 has some work too
+            for i in 0..3 {
+                fifo.add_task_on(1, Arc::new(FifoTask::new(i)));
+            }
+            fifo.add_task_on(3, Arc::new(FifoTask::new(9)));
+            (0..3)
+                .map(|_| {
+                    fifo.pick_next_task_as(0);
+                    fifo.last_steal_victim(0).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(victims(false), [1, 3, 1]);
+        assert_eq!(victims(true), [1, 1, 1]);
+    }
+
+    #[test]
+    fn run_next_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.set_run_next_slots(alloc::vec![
+            crate::FifoScheduler::new(),
+            crate::FifoScheduler::new()
+        ]);
+        HART.with(|h| h.set(0));
+        fifo.add_task(task(1));
+        fifo.add_task_run_next(task(2));
+        fifo.add_task_run_next(task(3)); // 2 goes back to the queue
+        assert_eq!((fifo.queue_len(0), fifo.run_next_len(0)), (2, 1));
+        // never stolen
+        assert_eq!(*fifo.pick_next_task_as(1).unwrap().inner(), 1);
+        let order: Vec<_> = (0..2)
+            .map(|_| *fifo.pick_next_task().unwrap().inner())
+            .collect();
+        assert_eq!(order, [3, 2]);
+        assert_eq!(fifo.run_next_len(0), 0);
+        // a slot follows its hart's queue, taking the target slot if free
+        HART.with(|h| h.set(1));
+        fifo.add_task_run_next(task(4));
+        fifo.add_task_run_next(task(5));
+        assert_eq!(fifo.flush_to_hart(1, 0), 2);
+        assert_eq!((fifo.run_next_len(0), fifo.run_next_len(1)), (1, 0));
+        HART.with(|h| h.set(0));
+        fifo.add_task_run_next(task(6));
+        assert_eq!(fifo.reinit(0), 3);
+        assert_eq!((fifo.run_next_len(0), fifo.queue_len(0)), (0, 0));
+    }
+
+    #[test]
+    fn run_next_batch_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_run_next_slots(alloc::vec![
+            crate::FifoScheduler::new(),
+            crate::FifoScheduler::new()
+        ]);
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task(task(1));
+        fifo.add_task(task(2));
+        fifo.add_task_run_next(task(3));
+        let mut out = Vec::with_capacity(4);
+        assert_eq!(fifo.pick_next_tasks_into(&mut out, 2), 2);
+        let picked: Vec<_> = out.iter().map(|task| *task.inner()).collect();
+        assert_eq!(picked, [3, 1]);
+        fifo.replenish_budget(0, 0);
+        assert_eq!(fifo.pick_next_tasks_into(&mut out, 2), 0);
+        fifo.replenish_budget(0, usize::MAX);
+        assert_eq!(fifo.pick_next_tasks_into(&mut out, 2), 1);
+        assert_eq!(*out[2].inner(), 2);
+    }
+
+    #[test]
+    fn pick_deadline_test() {
+        use core::cell::Cell;
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(2)));
+        // every read of the clock advances it
+        let clock = Cell::new(0);
+        let now = || {
+            clock.set(clock.get() + 1);
+            clock.get()
+        };
+        // past the deadline before the first probe
+        assert!(fifo.pick_next_task_deadline(0, now).is_none());
+        assert_eq!(fifo.snapshot().total, 2);
+        clock.set(0);
+        assert_eq!(*fifo.pick_next_task_deadline(10, now).unwrap().inner(), 1);
+        fifo.add_task(Arc::new(FifoTask::new(3)));
+        // the local queue is served regardless
+        assert_eq!(*fifo.pick_next_task_deadline(0, now).unwrap().inner(), 3);
+    }
+
+    #[test]
+    fn global_steal_epoch_test() {
+        // the victim each of harts 1 to 3 probes first when all go idle at
+        // once, every other hart having work
+        let first_probes = |epoch| {
+            let mut fifo = FifoSmpScheduler::<4, usize, ThreadLock, ThreadHart>::new();
+            fifo.set_global_steal_epoch(epoch);
+            fifo.init();
+            (1..4)
+                .map(|hart| {
+                    let start = fifo.hart_cursor(hart);
+                    (0..4)
+                        .map(|k| (start + k) % 4)
+                        .find(|&i| i != hart)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        // the naive scans all hit hart 0 first, the epoch spreads them out
+        assert_eq!(first_probes(false), [0, 0, 0]);
+        assert_eq!(first_probes(true), [2, 3, 0]);
+
+        let mut fifo = FifoSmpScheduler::<4, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_global_steal_epoch(true);
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(2)));
+        assert!(fifo.pick_next_task_as(1).is_some());
+        assert_eq!(fifo.last_steal_victim(1), Some(0));
+        // one steal advanced the epoch for every hart
+        assert_eq!((fifo.hart_cursor(0), fifo.hart_cursor(3)), (1, 0));
+    }
+
+    #[test]
+    fn steal_from_longest_test() {
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(2)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(3)));
+        assert_eq!(*fifo.steal_from_longest().unwrap().inner(), 2);
+        assert_eq!(fifo.last_steal_victim(0), Some(2));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(4)));
+        // the local queue is never a victim
+        assert_eq!(*fifo.steal_from_longest().unwrap().inner(), 1);
+        assert_eq!(*fifo.steal_from_longest().unwrap().inner(), 3);
+        assert!(fifo.steal_from_longest().is_none());
+        fifo.add_task_on(1, Arc::new(FifoTask::new(5)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(6)));
+        fifo.add_task_on(2, Arc::new(FifoTask::new(7)));
+        // a busy lock moves the thief on to the next longest
+        fifo.with_local(2, |_| {
+            assert_eq!(*fifo.steal_from_longest().unwrap().inner(), 5);
+        });
+        #[cfg(feature = "metrics")]
+        assert_eq!(fifo.stats(0).contention, 1);
+    }
+
+    #[test]
+    fn steal_from_longest_tracked_test() {
+        let task = || Arc::new(FifoTask::new(MetaTask(TaskMeta::new())));
+        let fifo = FifoSmpScheduler::<3, MetaTask, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task_on(1, task());
+        for _ in 0..2 {
+            let pinned = task();
+            pinned.task_meta().set_migratable(false);
+            fifo.add_task_on(2, pinned);
+        }
+        // the longest queue only holds pinned tasks, so hart 1 is robbed
+        let stolen = fifo.steal_from_longest_tracked().unwrap();
+        assert_eq!(stolen.task_meta().steal_count(), 1);
+        assert_eq!(fifo.last_steal_victim(0), Some(1));
+        assert!(fifo.steal_from_longest_tracked().is_none());
+        assert_eq!(fifo.queue_len(2), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "spin")]
+    fn spin_alias_test() {
+        let fifo = crate::FifoSpin::<2, usize, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn fair_steal_test() {
+        let fifo = FifoSmpScheduler::<4, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        for hart in 1..4 {
+            for i in 0..10 {
+                fifo.add_task_on(hart, Arc::new(FifoTask::new(hart * 100 + i)));
+            }
+        }
+        let mut stolen = [0; 4];
+        for _ in 0..12 {
+            stolen[*fifo.pick_next_task_as(0).unwrap().inner() / 100] += 1;
+        }
+        assert_eq!(stolen, [0, 4, 4, 4]);
+    }
+
+    #[test]
+    fn remove_tasks_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        let tasks: Vec<_> = (0..4).map(|i| Arc::new(FifoTask::new(i))).collect();
+        for (i, task) in tasks.iter().enumerate() {
+            fifo.add_task_on(i % 2, task.clone());
+        }
+        let gone = [
+            tasks[0].clone(),
+            tasks[1].clone(),
+            Arc::new(FifoTask::new(9)),
+        ];
+        assert_eq!(fifo.remove_tasks(&gone), 2);
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (1, 1));
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 2);
+    }
+
+    #[test]
+    fn steal_end_test() {
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_steal_end(crate::StealEnd::Tail);
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        assert_eq!(*fifo.pick_next_task_as(1).unwrap().inner(), 2);
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 0);
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn local_scheduler_test() {
+        let local = crate::LocalScheduler::<_, ThreadLock>::new(crate::FifoScheduler::new());
+        local.init();
+        let task = Arc::new(FifoTask::new(1));
+        local.add_task(task.clone());
+        local.add_task(Arc::new(FifoTask::new(2)));
+        assert_eq!(local.len(), 2);
+        assert!(local.remove_task(&task).is_some());
+        assert_eq!(*local.pick_next_task().unwrap().inner(), 2);
+        assert!(local.is_empty());
+    }
+
+    #[test]
+    fn wake_hart_test() {
+        use core::sync::atomic::AtomicUsize;
+        #[derive(Default)]
+        struct Waker(AtomicUsize);
+        impl crate::WakeHart for Waker {
+            fn wake(&self, hart_id: usize, new_len: usize) {
+                assert_eq!(hart_id, 1);
+                self.0.store(new_len, Ordering::Relaxed);
+            }
+        }
+        let waker = Arc::new(Waker::default());
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_waker(waker.clone());
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(2)));
+        assert_eq!(waker.0.load(Ordering::Relaxed), 2);
+        // enqueueing on itself never wakes the current hart
+        fifo.add_task(Arc::new(FifoTask::new(3)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(4)));
+        assert_eq!(waker.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn batch_pick_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        for i in 0..5 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        fifo.add_task_on(1, Arc::new(FifoTask::new(5)));
+        let mut out = Vec::with_capacity(3);
+        assert_eq!(fifo.pick_next_tasks_into(&mut out, 3), 3);
+        assert_eq!(
+            out.iter().map(|t| *t.inner()).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        let mut slots = [None, None, None];
+        assert_eq!(fifo.pick_next_tasks_slice(&mut slots), 2);
+        assert!(slots[2].is_none());
+        // the local queue is empty, so a single task is stolen
+        out.clear();
+        assert_eq!(fifo.pick_next_tasks_into(&mut out, 3), 1);
+        assert_eq!(*out[0].inner(), 5);
+        #[cfg(feature = "metrics")]
+        assert_eq!(fifo.total_picks(), 6);
+    }
+
+    #[test]
+    fn offline_hart_test() {
+        let task = |v| Arc::new(FifoTask::new(v));
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.scale_harts(2);
+        assert!(!fifo.is_hart_online(2));
+        fifo.add_task_on(2, task(1));
+        assert!(fifo.try_add_task_on(2, task(2)).is_err());
+        fifo.add_task_on(1, task(3));
+        assert_eq!(fifo.flush_to_hart(1, 2), 0);
+        assert_eq!(fifo.harts_with_work().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(fifo.reinit(1), 1);
+        assert_eq!(fifo.queue_len(1), 0);
+        fifo.add_task_on(1, task(3));
+        HART.with(|h| h.set(2));
+        fifo.add_task(task(4));
+        fifo.put_prev_task(task(5), true);
+        assert!(fifo.try_add_task(task(6)).is_ok());
+        assert_eq!(fifo.queue_len(2), 0);
+        assert_eq!(fifo.queue_len(0) + fifo.queue_len(1), 5);
+    }
+
+    #[test]
+    fn age_tasks_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        for i in 0..3 {
+            fifo.add_task_on(1, Arc::new(FifoTask::new(i)));
+        }
+        let mut seen = Vec::new();
+        let visited = fifo.age_tasks(1, |task| {
+            seen.push(*task.inner());
+            Some(-1)
+        });
+        assert_eq!(visited, 3);
+        assert_eq!(seen, [0, 1, 2]);
+        assert_eq!(fifo.queue_len(1), 3);
+        assert_eq!(*fifo.pick_next_task_as(1).unwrap().inner(), 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "metrics"))]
+    fn no_metrics_test() {
+        // nothing is counted in the pick path without metrics
+        assert_eq!(core::mem::size_of::<crate::stats::HartCounters>(), 0);
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        assert!(fifo.pick_next_task_as(0).is_some());
+    }
+
+    #[test]
+    fn fallback_source_test() {
+        struct Inbox(std::sync::Mutex<Vec<usize>>);
+        impl crate::FallbackSource<Arc<FifoTask<usize>>> for Inbox {
+            fn supply(&self, _hart_id: usize) -> Option<Arc<FifoTask<usize>>> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .pop()
+                    .map(|v| Arc::new(FifoTask::new(v)))
+            }
+        }
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_fallback(Arc::new(Inbox(std::sync::Mutex::new(alloc::vec![2]))));
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 1);
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 2);
+        assert!(fifo.pick_next_task_as(0).is_none());
+    }
+
+    #[test]
+    fn block_current_test() {
+        let fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        fifo.add_task(Arc::new(FifoTask::new(3)));
+        let running = fifo.pick_next_task().unwrap();
+        let blocked = fifo.block_current(running);
+        assert!(fifo.pick_next_task().is_none());
+        // woken up
+        fifo.add_task(blocked);
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 3);
+        #[cfg(feature = "replay")]
+        assert_eq!(fifo.replay_log()[2].op, crate::ReplayOp::Block);
+    }
+
+    #[test]
+    #[cfg(feature = "replay")]
+    fn replay_log_test() {
+        use crate::ReplayOp;
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_task_id(|task| *task.inner());
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(7)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(8)));
+        fifo.pick_next_task_as(0);
+        fifo.pick_next_task_as(1);
+        let log: Vec<_> = fifo
+            .replay_log()
+            .iter()
+            .map(|e| (e.seq, e.hart, e.op, e.task))
+            .collect();
+        assert_eq!(
+            log,
+            [
+                (0, 0, ReplayOp::Add, 7),
+                (1, 0, ReplayOp::Add, 8),
+                (2, 0, ReplayOp::Pick, 7),
+                (3, 1, ReplayOp::Steal { from: 0 }, 8),
+            ]
+        );
+        for i in 0..crate::REPLAY_LEN {
+            fifo.add_task_on(1, Arc::new(FifoTask::new(i)));
+        }
+        let log = fifo.replay_log();
+        assert_eq!(log.len(), crate::REPLAY_LEN);
+        assert_eq!(log[0].seq, 4);
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn tracepoint_test() {
+        use crate::{register_tracepoints, unregister_tracepoints, Tracepoints};
+        std::thread_local! {
+            static EVENTS: core::cell::RefCell<Vec<(&'static str, usize, usize)>> =
+                const { core::cell::RefCell::new(Vec::new()) };
+        }
+        // other tests fire the probes too, only events of this thread count
+        static PROBES: Tracepoints = Tracepoints {
+            enqueue: |hart, task| EVENTS.with(|e| e.borrow_mut().push(("enqueue", hart, task))),
+            pick: |hart, task| EVENTS.with(|e| e.borrow_mut().push(("pick", hart, task))),
+            steal: |from, to, task| {
+                EVENTS.with(|e| e.borrow_mut().push(("steal", from * 10 + to, task)))
+            },
+        };
+        register_tracepoints(&PROBES);
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_task_id(|task| *task.inner());
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(7)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(8)));
+        fifo.pick_next_task_as(0);
+        fifo.pick_next_task_as(1);
+        unregister_tracepoints();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(9)));
+        assert_eq!(
+            EVENTS.with(|e| e.borrow().clone()),
+            [
+                ("enqueue", 0, 7),
+                ("enqueue", 0, 8),
+                ("pick", 0, 7),
+                ("steal", 1, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn placement_test() {
+        use crate::Placement;
+        let fifo = FifoSmpScheduler::<1, usize, ThreadLock, crate::LocalHart>::new();
+        fifo.init();
+        fifo.add_task(Arc::new(FifoTask::new(1)));
+        fifo.put_prev_task_ex(Arc::new(FifoTask::new(2)), Placement::Front);
+        fifo.put_prev_task_ex(Arc::new(FifoTask::new(3)), Placement::Back);
+        fifo.put_prev_task_ex(Arc::new(FifoTask::new(4)), Placement::ByPriority);
+        let order: Vec<_> = (0..4)
+            .map(|_| *fifo.pick_next_task().unwrap().inner())
+            .collect();
+        assert_eq!(order, [2, 1, 3, 4]);
+    }
+
+    #[test]
+    fn add_task_fallible_test() {
+        use crate::RefFifoSmpScheduler;
+        let fifo = RefFifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(1));
+        assert!(fifo.add_task_fallible(Arc::new(FifoTask::new(1))).is_ok());
+        assert_eq!((fifo.queue_len(0), fifo.queue_len(1)), (0, 1));
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 1);
+    }
+
+    #[test]
+    fn pinned_task_test() {
+        use crate::RefFifoSmpScheduler;
+        use core::cell::Cell;
+        use core::marker::PhantomPinned;
+        use core::pin::Pin;
+        // points into itself once pinned
+        struct SelfRef {
+            value: usize,
+            this: Cell<*const usize>,
+            _pin: PhantomPinned,
+        }
+        type Pinned = Pin<Arc<FifoTask<SelfRef>>>;
+        let fifo = RefFifoSmpScheduler::<2, SelfRef, ThreadLock, ThreadHart, Pinned>::new();
+        fifo.init();
+        let task: Pinned = Arc::pin(FifoTask::new(SelfRef {
+            value: 7,
+            this: Cell::new(core::ptr::null()),
+            _pin: PhantomPinned,
+        }));
+        task.inner().this.set(&task.inner().value);
+        fifo.add_task_on(0, task.clone());
+        fifo.add_task_on(
+            0,
+            Arc::pin(FifoTask::new(SelfRef {
+                value: 8,
+                this: Cell::new(core::ptr::null()),
+                _pin: PhantomPinned,
+            })),
+        );
+        // stolen by hart 1, the task itself stays put
+        let stolen = fifo.pick_next_task_as(1).unwrap();
+        assert_eq!(stolen.inner().this.get(), &stolen.inner().value as *const _);
+        assert_eq!(unsafe { *stolen.inner().this.get() }, 7);
+        assert_eq!(fifo.remove_tasks(&[task]), 0);
+    }
+
+    #[test]
+    fn min_remain_test() {
+        let mut fifo = FifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.set_min_remain(1);
+        fifo.init();
+        fifo.add_task_on(0, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(2)));
+        // hart 1 takes one task but leaves hart 0 its last one
+        assert_eq!(*fifo.pick_next_task_as(1).unwrap().inner(), 1);
+        assert!(fifo.pick_next_task_as(1).is_none());
+        fifo.add_task_on(1, Arc::new(FifoTask::new(3)));
+        // and hart 0, once idle, does not take it back
+        assert_eq!(*fifo.pick_next_task_as(0).unwrap().inner(), 2);
+        assert!(fifo.pick_next_task_as(0).is_none());
+        HART.with(|h| h.set(0));
+        assert!(fifo.steal_from_longest().is_none());
+    }
+
+    #[test]
+    fn scheduler_ext_test() {
+        use crate::SchedulerExt;
+        fn drive<Sched: SchedulerExt<Item = Arc<FifoTask<usize>>>>(sched: &Sched) -> usize {
+            sched.init();
+            sched.add_task(Arc::new(FifoTask::new(1)));
+            sched.add_task(Arc::new(FifoTask::new(2)));
+            assert_eq!(sched.total_len(), 2);
+            *sched.pick_next_task().unwrap().inner()
+        }
+        let fifo = FifoSmpScheduler::<1, usize, ThreadLock, crate::LocalHart>::new();
+        assert_eq!(drive(&*fifo), 1);
+        let local = crate::LocalScheduler::<_, ThreadLock>::new(crate::FifoScheduler::new());
+        assert_eq!(drive(&local), 1);
+    }
+
+    #[test]
+    fn add_task_spread_test() {
+        let fifo = FifoSmpScheduler::<3, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(0)));
+        for i in 0..3 {
+            fifo.add_task_spread(7, Arc::new(FifoTask::new(i)));
+        }
+        // each hart got one member despite hart 1 being busier
+        assert_eq!(
+            (0..3).map(|i| fifo.queue_len(i)).collect::<Vec<_>>(),
+            [1, 2, 1]
+        );
+        // all harts are used, so the next member is balanced
+        fifo.add_task_spread(7, Arc::new(FifoTask::new(3)));
+        assert_eq!(fifo.queue_len(0), 2);
+        fifo.clear_family(7);
+        fifo.add_task_spread(7, Arc::new(FifoTask::new(4)));
+        assert_eq!(fifo.queue_len(2), 2);
+    }
+
+    #[test]
+    fn counter_wrap_test() {
+        use crate::stats::{saturating_sub, wrapping_sum};
+        assert_eq!(wrapping_sum([usize::MAX, 2].into_iter()), 1);
+        let len = AtomicUsize::new(1);
+        assert_eq!(saturating_sub(&len, 2), 1);
+        assert_eq!(len.load(Ordering::Relaxed), 0);
+        #[cfg(feature = "metrics")]
+        {
+            let counters = crate::stats::HartCounters::starting_at(usize::MAX);
+            let before = counters.load().picks;
+            counters.record_pick();
+            counters.record_pick();
+            assert_eq!(counters.load().picks, 1);
+            assert_eq!(counters.load().picks.wrapping_sub(before), 2);
+        }
+    }
+
+    #[test]
+    fn on_empty_test() {
+        static EMPTIED: AtomicUsize = AtomicUsize::new(0);
+        let mut fifo = FifoSmpScheduler::<2, usize, spin::Mutex<()>, ScheduleHartImpl>::new();
+        fifo.set_on_empty(|hart| {
+            assert_eq!(hart, 1);
+            EMPTIED.fetch_add(1, Ordering::SeqCst);
+        });
+        fifo.init();
+        fifo.add_task_on(1, Arc::new(FifoTask::new(1)));
+        fifo.add_task_on(1, Arc::new(FifoTask::new(2)));
+        assert!(fifo.pick_next_task_as(0).is_some());
+        assert_eq!(EMPTIED.load(Ordering::SeqCst), 0);
+        assert!(fifo.pick_next_task_as(1).is_some());
+        assert_eq!(EMPTIED.load(Ordering::SeqCst), 1);
+        assert!(fifo.pick_next_task_as(1).is_none());
+        assert_eq!(EMPTIED.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn wait_for_task_test() {
+        use crate::StdParker;
+        let mut fifo = FifoSmpScheduler::<2, usize, spin::Mutex<()>, ThreadHart>::new();
+        fifo.set_parker(Arc::new(StdParker::new(2)));
+        fifo.init();
+        let fifo = Arc::new(fifo);
+        let waiter = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || {
+                HART.with(|h| h.set(1));
+                *fifo.wait_for_task().inner()
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fifo.add_task_on(0, Arc::new(FifoTask::new(7))); // hart 0 is busy, hart 1 steals it
+        assert_eq!(waiter.join().unwrap(), 7);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn block_overflow_test() {
+        use crate::{OverflowPolicy, StdParker};
+        let mut fifo = FifoSmpScheduler::<2, usize, spin::Mutex<()>, ThreadHart>::new();
+        fifo.set_capacity(Some(1), OverflowPolicy::Block);
+        fifo.set_parker(Arc::new(StdParker::new(2)));
+        fifo.init();
+        let fifo = Arc::new(fifo);
+        let producer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || {
+                HART.with(|h| h.set(0));
+                for i in 0..4 {
+                    assert!(fifo.try_add_task(Arc::new(FifoTask::new(i))).is_ok());
+                    assert!(fifo.queue_len(0) <= 1);
+                }
+            })
+        };
+        HART.with(|h| h.set(1));
+        let mut got = Vec::new();
+        while got.len() < 4 {
+            match fifo.pick_next_task() {
+                Some(task) => got.push(*task.inner()),
+                None => std::thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(got, [0, 1, 2, 3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn run_thread_per_hart_test() {
+        use crate::ThreadLocalHart;
+        static RUN: AtomicUsize = AtomicUsize::new(0);
+        let fifo = FifoSmpScheduler::<3, usize, spin::Mutex<()>, ThreadLocalHart>::new();
+        fifo.init();
+        for i in 0..4 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        fifo.run_thread_per_hart(|hart_id, task| {
+            RUN.fetch_add(1, Ordering::SeqCst);
+            // tasks queue a follower until they count down to zero
+            if *task.inner() > 0 {
+                fifo.add_task_on(hart_id, Arc::new(FifoTask::new(task.inner() - 1)));
+            }
+        });
+        assert_eq!(RUN.load(Ordering::SeqCst), 1 + 2 + 3 + 4);
+        assert_eq!(fifo.snapshot().total, 0);
+    }
+
+    #[cfg(feature = "irq")]
+    #[test]
+    fn irq_safe_mutex_test() {
+        use crate::{IrqControl, IrqSafeMutex};
+        use core::cell::{Cell, RefCell};
+        use std::boxed::Box;
+        std::thread_local! {
+            static IRQ_ON: Cell<bool> = const { Cell::new(true) };
+            static PENDING: RefCell<Option<Box<dyn FnOnce()>>> = const { RefCell::new(None) };
+        }
+        struct FakeIrq;
+        impl IrqControl for FakeIrq {
+            fn save_and_disable() -> usize {
+                IRQ_ON.with(|on| on.replace(false)) as usize
+            }
+            fn restore(state: usize) {
+                IRQ_ON.with(|on| on.set(state != 0));
+                if state != 0 {
+                    if let Some(handler) = PENDING.with(|p| p.borrow_mut().take()) {
+                        handler();
+                    }
+                }
+            }
+        }
+        fn raise_irq(handler: Box<dyn FnOnce()>) {
+            if IRQ_ON.with(|on| on.get()) {
+                handler();
+            } else {
+                PENDING.with(|p| *p.borrow_mut() = Some(handler));
+            }
+        }
+        let queue = std::sync::Arc::new(lock_api::Mutex::<IrqSafeMutex<FakeIrq>, usize>::new(0));
+        let guard = queue.lock();
+        let handler_queue = queue.clone();
+        // the handler would spin forever if it ran while the lock is held
+        raise_irq(Box::new(move || *handler_queue.lock() += 1));
+        assert_eq!(*guard, 0);
+        drop(guard);
+        assert_eq!(*queue.lock(), 1);
+        assert!(IRQ_ON.with(|on| on.get()));
+    }
+
+    #[cfg(feature = "irq")]
+    #[test]
+    fn irq_safe_mutex_nesting_test() {
+        use crate::{IrqControl, IrqSafeMutex};
+        use core::cell::Cell;
+        std::thread_local! {
+            static IRQ_ON: Cell<bool> = const { Cell::new(true) };
+            static DEPTH: Cell<usize> = const { Cell::new(0) };
+        }
+        struct NestedIrq;
+        impl IrqControl for NestedIrq {
+            fn save_and_disable() -> usize {
+                DEPTH.with(|depth| depth.set(depth.get() + 1));
+                IRQ_ON.with(|on| on.replace(false)) as usize
+            }
+            fn restore(state: usize) {
+                let depth = DEPTH.with(|depth| depth.replace(depth.get() - 1)) - 1;
+                // interrupts come back on only once no lock is held
+                assert!(
+                    state == 0 || depth == 0,
+                    "interrupts enabled with a lock held"
+                );
+                IRQ_ON.with(|on| on.set(state != 0));
+            }
+        }
+        let fifo = FifoSmpScheduler::<3, usize, IrqSafeMutex<NestedIrq>, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        for i in 0..4 {
+            fifo.add_task_on(0, Arc::new(FifoTask::new(i)));
+        }
+        // two queues are held at once, locked in either order
+        assert_eq!(fifo.migrate_half_to_idle(), 2);
+        assert_eq!(fifo.flush_to_hart(1, 0), 2);
+        assert_eq!(fifo.flush_to_hart(0, 2), 4);
+        let guards = fifo.try_lock_all().unwrap();
+        drop(guards);
+        // a failed try_lock_all releases what it got
+        fifo.with_local(2, |_| assert!(fifo.try_lock_all().is_none()));
+        assert_eq!(DEPTH.with(|depth| depth.get()), 0);
+        assert!(IRQ_ON.with(|on| on.get()));
+        let plain = FifoSmpScheduler::<2, usize, crate::AtomicRawMutex, ThreadHart>::new();
+        plain.init();
+        plain.add_task_on(1, Arc::new(FifoTask::new(7)));
+        assert_eq!(*plain.pick_next_task().unwrap().inner(), 7);
+    }
+
+    #[cfg(feature = "edf")]
+    #[test]
+    fn edf_test() {
+        use crate::{EdfSmpScheduler, EdfTask};
+        let edf = EdfSmpScheduler::<2, usize, spin::Mutex<()>, ScheduleHartImpl>::new();
+        edf.init();
+        edf.add_task_on(1, Arc::new(EdfTask::new(1, 30)));
+        edf.add_task_on(1, Arc::new(EdfTask::new(2, 10)));
+        edf.add_task_on(0, Arc::new(EdfTask::new(3, 20)));
+        edf.add_task_on(0, Arc::new(EdfTask::new(4, 20)));
+        assert_eq!(*edf.pick_next_task_as(0).unwrap().inner(), 3);
+        assert_eq!(*edf.pick_next_task_as(0).unwrap().inner(), 4);
+        // steal the earliest deadline of hart 1
+        assert_eq!(*edf.pick_next_task_as(0).unwrap().inner(), 2);
+        assert_eq!(*edf.pick_next_task_as(0).unwrap().inner(), 1);
+    }
+
+    #[cfg(feature = "weak")]
+    #[test]
+    fn weak_fifo_test() {
+        use crate::WeakFifoSmpScheduler;
+        let fifo = WeakFifoSmpScheduler::<2, usize, spin::Mutex<()>, ScheduleHartImpl>::new();
+        fifo.init();
+        let dead = Arc::new(FifoTask::new(1));
+        let alive = Arc::new(FifoTask::new(2));
+        fifo.add_task_on(0, dead.clone());
+        fifo.add_task_on(1, alive.clone());
+        drop(dead);
+        let task = fifo.pick_next_task_as(0); // skip the dead task, steal from hart 1
+        assert!(task.is_some());
+        assert_eq!(*task.unwrap().inner(), 2);
+        drop(alive);
+        assert!(fifo.pick_next_task_as(1).is_none());
+    }
+
+    #[cfg(feature = "weak")]
+    #[test]
+    fn weak_fifo_len_test() {
+        use crate::WeakFifoSmpScheduler;
+        let fifo = WeakFifoSmpScheduler::<2, usize, ThreadLock, ThreadHart>::new();
+        fifo.init();
+        HART.with(|h| h.set(0));
+        let add = |n| {
+            let tasks: Vec<_> = (0..n).map(|i| Arc::new(FifoTask::new(i))).collect();
+            for task in &tasks {
+                fifo.add_task_on(0, task.clone());
+            }
+            tasks
+        };
+        let mut tasks = add(3);
+        drop(tasks.remove(2));
+        drop(tasks.remove(0));
+        assert_eq!(fifo.queue_len(0), 3);
+        // the pick discards both dead tasks, not only the one it returns
+        assert_eq!(*fifo.pick_next_task().unwrap().inner(), 1);
+        assert_eq!(fifo.queue_len(0), 0);
+        assert_eq!(fifo.snapshot().total, 0);
+        let mut tasks = add(3);
+        drop(tasks.remove(1));
+        drop(tasks.remove(0));
+        assert!(fifo.remove_task(&tasks[0]).is_some());
+        assert_eq!(fifo.queue_len(0), 0);
+    }
 }
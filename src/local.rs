@@ -0,0 +1,87 @@
+use crate::smp::SmpScheduler;
+use crate::ScheduleHart;
+use alloc::vec;
+use core::ops::Deref;
+use scheduler::BaseScheduler;
+
+/// The only hart of a [`LocalScheduler`].
+pub struct LocalHart;
+
+impl ScheduleHart for LocalHart {
+    fn hart_id() -> usize {
+        0
+    }
+
+    fn hart_count() -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// A single locked queue with the method surface of an smpscheduler but no
+/// harts and no stealing.
+///
+/// Meant for testing task types against the scheduler contract in isolation;
+/// it runs the same code as the smpschedulers do for a single hart.
+pub struct LocalScheduler<S: BaseScheduler, L: lock_api::RawMutex> {
+    inner: SmpScheduler<1, S, L, LocalHart>,
+}
+
+impl<S: BaseScheduler, L: lock_api::RawMutex> LocalScheduler<S, L> {
+    /// The number of harts, always 1, usable in const contexts.
+    pub const NR_HARTS: usize = 1;
+
+    /// Creates a new [`LocalScheduler`] around `scheduler`.
+    pub fn new(scheduler: S) -> Self
+    where
+        S::SchedItem: Deref,
+    {
+        Self {
+            inner: SmpScheduler::new(vec![scheduler]),
+        }
+    }
+
+    /// Initializes the queue, which must happen before it is used.
+    pub fn init(&self) {
+        self.inner.init();
+    }
+
+    /// Adds a task to the queue.
+    pub fn add_task(&self, task: S::SchedItem) {
+        self.inner.add_task(task);
+    }
+
+    /// Removes a task from the queue.
+    pub fn remove_task(&self, task: &S::SchedItem) -> Option<S::SchedItem> {
+        self.inner.remove_task(task)
+    }
+
+    /// Picks the next task.
+    pub fn pick_next_task(&self) -> Option<S::SchedItem> {
+        self.inner.pick_next_task()
+    }
+
+    /// Puts the current task back after it was preempted or yielded.
+    pub fn put_prev_task(&self, prev: S::SchedItem, preempt: bool) {
+        self.inner.put_prev_task(prev, preempt);
+    }
+
+    /// Ticks the current task, returning whether to reschedule.
+    pub fn task_tick(&self, current: &S::SchedItem) -> bool {
+        self.inner.task_tick(current)
+    }
+
+    /// Sets the priority of a task.
+    pub fn set_priority(&self, task: &S::SchedItem, prio: isize) -> bool {
+        self.inner.set_priority(task, prio)
+    }
+
+    /// Returns the number of tasks currently queued.
+    pub fn len(&self) -> usize {
+        self.inner.queue_len(0)
+    }
+
+    /// Returns true if no task is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,129 @@
+#[cfg(feature = "replay")]
+use alloc::vec::Vec;
+#[cfg(feature = "replay")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of decisions kept by the replay log, older ones are overwritten.
+#[cfg(feature = "replay")]
+pub const REPLAY_LEN: usize = 256;
+
+/// A scheduling decision recorded in the replay log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayOp {
+    /// a task was added to the hart
+    Add,
+    /// the hart picked a task from its own queue
+    Pick,
+    /// the hart stole a task from hart `from`
+    Steal {
+        /// the victim
+        from: usize,
+    },
+    /// the task running on the hart blocked and left scheduling
+    Block,
+}
+
+impl ReplayOp {
+    #[cfg(feature = "replay")]
+    fn encode(self) -> usize {
+        match self {
+            ReplayOp::Add => 0,
+            ReplayOp::Pick => 1,
+            ReplayOp::Block => 2,
+            ReplayOp::Steal { from } => from + 3,
+        }
+    }
+
+    #[cfg(feature = "replay")]
+    fn decode(op: usize) -> Self {
+        match op {
+            0 => ReplayOp::Add,
+            1 => ReplayOp::Pick,
+            2 => ReplayOp::Block,
+            from => ReplayOp::Steal { from: from - 3 },
+        }
+    }
+}
+
+/// One entry of the replay log.
+#[cfg(feature = "replay")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayEntry {
+    /// position of the decision among all recorded ones, starting at 0
+    pub seq: usize,
+    /// the hart the decision was made for
+    pub hart: usize,
+    /// what happened
+    pub op: ReplayOp,
+    /// the task involved, as identified by the replay id function
+    pub task: usize,
+}
+
+/// A slot of the ring, `stamp` being the sequence number of the entry it
+/// holds, or `usize::MAX` while it is written.
+#[cfg(feature = "replay")]
+struct Slot {
+    stamp: AtomicUsize,
+    hart: AtomicUsize,
+    op: AtomicUsize,
+    task: AtomicUsize,
+}
+
+/// A lock-free ring of the last [`REPLAY_LEN`] decisions.
+#[cfg(feature = "replay")]
+pub(crate) struct ReplayLog {
+    next: AtomicUsize,
+    slots: Vec<Slot>,
+}
+
+#[cfg(feature = "replay")]
+impl ReplayLog {
+    pub fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            slots: (0..REPLAY_LEN)
+                .map(|_| Slot {
+                    stamp: AtomicUsize::new(usize::MAX),
+                    hart: AtomicUsize::new(0),
+                    op: AtomicUsize::new(0),
+                    task: AtomicUsize::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn record(&self, hart: usize, op: ReplayOp, task: usize) {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[seq % REPLAY_LEN];
+        slot.stamp.store(usize::MAX, Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        slot.hart.store(hart, Ordering::Relaxed);
+        slot.op.store(op.encode(), Ordering::Relaxed);
+        slot.task.store(task, Ordering::Relaxed);
+        slot.stamp.store(seq, Ordering::Release);
+    }
+
+    /// Returns the entries in sequence order, skipping those being written
+    /// or overwritten while we read them.
+    pub fn entries(&self) -> Vec<ReplayEntry> {
+        let mut entries = Vec::with_capacity(REPLAY_LEN);
+        for slot in self.slots.iter() {
+            let seq = slot.stamp.load(Ordering::Acquire);
+            if seq == usize::MAX {
+                continue;
+            }
+            let entry = ReplayEntry {
+                seq,
+                hart: slot.hart.load(Ordering::Relaxed),
+                op: ReplayOp::decode(slot.op.load(Ordering::Relaxed)),
+                task: slot.task.load(Ordering::Relaxed),
+            };
+            core::sync::atomic::fence(Ordering::Acquire);
+            if slot.stamp.load(Ordering::Relaxed) == seq {
+                entries.push(entry);
+            }
+        }
+        entries.sort_unstable_by_key(|entry| entry.seq);
+        entries
+    }
+}
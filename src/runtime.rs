@@ -0,0 +1,56 @@
+use crate::smp::SmpScheduler;
+use crate::ThreadLocalHart;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use scheduler::BaseScheduler;
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex>
+    SmpScheduler<SMP, S, L, ThreadLocalHart>
+where
+    Self: Sync,
+{
+    /// Runs the scheduler with one OS thread per hart, for prototyping and
+    /// benchmarks.
+    ///
+    /// Each thread registers its hart with [`ThreadLocalHart`], then picks
+    /// tasks and hands them to `worker` along with its hart id, which may
+    /// queue more work. A thread stops once its pick comes up empty while no
+    /// task is queued and no worker is running, and this returns when every
+    /// thread has stopped. The scheduler must be initialized.
+    pub fn run_thread_per_hart(&self, worker: impl Fn(usize, S::SchedItem) + Sync) {
+        let running = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for hart_id in 0..SMP {
+                let (running, worker) = (&running, &worker);
+                scope.spawn(move || {
+                    ThreadLocalHart::register(hart_id);
+                    loop {
+                        match self.pick_next_task() {
+                            Some(task) => {
+                                running.fetch_add(1, Ordering::SeqCst);
+                                worker(hart_id, task);
+                                running.fetch_sub(1, Ordering::SeqCst);
+                            }
+                            // a running worker may still queue more tasks
+                            None if running.load(Ordering::SeqCst) > 0
+                                || !self.nothing_queued() =>
+                            {
+                                std::thread::yield_now()
+                            }
+                            None => break,
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Returns true if every queue of the scheduler reads empty.
+    fn nothing_queued(&self) -> bool {
+        #[cfg(feature = "urgent")]
+        let urgent = (0..SMP).map(|i| self.urgent_len(i)).sum::<usize>();
+        #[cfg(not(feature = "urgent"))]
+        let urgent = 0;
+        let run_next = (0..SMP).map(|i| self.run_next_len(i)).sum::<usize>();
+        self.snapshot().total + urgent + run_next + self.overflow_len() == 0
+    }
+}
@@ -0,0 +1,217 @@
+use crate::smp::{drain_queue, refill_queue, same_address, Same};
+use crate::stats::{saturating_sub, wrapping_sum, HartCounters};
+use crate::{ForEachTask, HartSnapshot, SchedSnapshot, ScheduleHart};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use scheduler::BaseScheduler;
+
+/// An smpscheduler whose queues sit behind reader-writer locks, for
+/// read-heavy introspection.
+///
+/// The other smpschedulers lock a queue exclusively even to look at it, so
+/// monitors and dumps reading the queues serialize against each other and
+/// against the hart. Here they take the read lock:
+/// [`with_local`](Self::with_local) and
+/// [`for_each_task`](Self::for_each_task) proceed concurrently, and only the
+/// operations changing a queue take the write lock. Lengths and
+/// [snapshots](Self::snapshot) take no lock at all, as elsewhere.
+///
+/// This is a parallel type offering the core operations only: adding,
+/// removing, picking with work stealing, putting back, ticking and
+/// reprioritizing. The policies and hooks of the `RawMutex`-locked
+/// schedulers are not available on it.
+pub struct RwSmpScheduler<
+    const SMP: usize,
+    S: BaseScheduler,
+    L: lock_api::RawRwLock,
+    H: ScheduleHart,
+> {
+    queues: Vec<lock_api::RwLock<L, S>>,
+    /// the length of every queue, only changed under its write lock
+    lens: [AtomicUsize; SMP],
+    stats: [HartCounters; SMP],
+    /// matches the task to remove, by address
+    identity: Same<S::SchedItem>,
+    initialized: AtomicBool,
+    _hart: PhantomData<H>,
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawRwLock, H: ScheduleHart>
+    RwSmpScheduler<SMP, S, L, H>
+{
+    /// The number of harts, usable in const contexts.
+    pub const NR_HARTS: usize = SMP;
+
+    /// Creates a new empty [`RwSmpScheduler`], `schedulers[i]` being the
+    /// queue of hart `i`.
+    pub fn new(schedulers: Vec<S>) -> Self
+    where
+        S::SchedItem: Deref,
+    {
+        assert_eq!(schedulers.len(), SMP);
+        Self {
+            queues: schedulers.into_iter().map(lock_api::RwLock::new).collect(),
+            lens: core::array::from_fn(|_| AtomicUsize::new(0)),
+            stats: core::array::from_fn(|_| HartCounters::default()),
+            identity: same_address,
+            initialized: AtomicBool::new(false),
+            _hart: PhantomData,
+        }
+    }
+
+    /// Initializes every queue, which must happen once, before the
+    /// scheduler is used.
+    pub fn init(&self) {
+        for queue in self.queues.iter() {
+            queue.write().init();
+        }
+        self.initialized.store(true, Ordering::Release);
+    }
+
+    /// Panics unless [`init`](Self::init) has been called.
+    #[inline]
+    fn check_init(&self) {
+        assert!(
+            self.initialized.load(Ordering::Relaxed),
+            "RwSmpScheduler used before init(): call init() first"
+        );
+    }
+
+    /// Adds a task to the current hart.
+    pub fn add_task(&self, task: S::SchedItem) {
+        self.add_task_on(H::hart_id(), task);
+    }
+
+    /// Adds a task to the queue of `hart_id`.
+    pub fn add_task_on(&self, hart_id: usize, task: S::SchedItem) {
+        self.check_init();
+        let mut queue = self.queues[hart_id].write();
+        queue.add_task(task);
+        self.lens[hart_id].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Removes `task` from whichever hart it is queued on, looking at the
+    /// current hart first.
+    ///
+    /// The task is matched by address on each queue drained under its write
+    /// lock, as `SmpScheduler::remove_task` does, rather than with the inner
+    /// `remove_task`, which for CFS could take out another hart's task
+    /// sharing its key.
+    pub fn remove_task(&self, task: &S::SchedItem) -> Option<S::SchedItem> {
+        self.check_init();
+        let hart_id = H::hart_id();
+        (0..SMP).map(|i| (hart_id + i) % SMP).find_map(|i| {
+            let mut queue = self.queues[i].write();
+            let mut tasks = drain_queue(&mut *queue);
+            let found = tasks
+                .iter()
+                .position(|queued| (self.identity)(queued, task));
+            let task = found.map(|i| tasks.remove(i));
+            refill_queue(&mut *queue, tasks);
+            let task = task?;
+            saturating_sub(&self.lens[i], 1);
+            Some(task)
+        })
+    }
+
+    /// Picks the next task of the current hart, stealing one from the other
+    /// harts in turn when its queue is empty.
+    ///
+    /// Victims are only ever `try_write` locked: one being read, or written
+    /// by its own hart, is passed over rather than waited for.
+    pub fn pick_next_task(&self) -> Option<S::SchedItem> {
+        let hart_id = H::hart_id();
+        self.check_init();
+        let local = self.queues[hart_id].write().pick_next_task();
+        if let Some(task) = local {
+            saturating_sub(&self.lens[hart_id], 1);
+            self.stats[hart_id].record_pick();
+            return Some(task);
+        }
+        let victims = (1..SMP).map(|i| (hart_id + i) % SMP);
+        for victim in victims.filter(|&i| self.queue_len(i) > 0) {
+            let Some(mut queue) = self.queues[victim].try_write() else {
+                self.stats[hart_id].record_contention();
+                continue;
+            };
+            let Some(task) = queue.pick_next_task() else {
+                continue;
+            };
+            saturating_sub(&self.lens[victim], 1);
+            drop(queue);
+            self.stats[hart_id].record_steal();
+            self.stats[hart_id].record_pick();
+            return Some(task);
+        }
+        None
+    }
+
+    /// Puts the current task back on the current hart after it was
+    /// preempted or yielded.
+    pub fn put_prev_task(&self, prev: S::SchedItem, preempt: bool) {
+        let hart_id = H::hart_id();
+        let mut queue = self.queues[hart_id].write();
+        queue.put_prev_task(prev, preempt);
+        self.lens[hart_id].fetch_add(1, Ordering::Relaxed);
+        drop(queue);
+        if preempt {
+            self.stats[hart_id].record_preempt();
+        }
+    }
+
+    /// Ticks the task running on the current hart, returning whether to
+    /// reschedule.
+    pub fn task_tick(&self, current: &S::SchedItem) -> bool {
+        self.queues[H::hart_id()].write().task_tick(current)
+    }
+
+    /// Sets the priority of `task`, on whichever hart it is queued on.
+    pub fn set_priority(&self, task: &S::SchedItem, prio: isize) -> bool {
+        (0..SMP).any(|i| self.queues[i].write().set_priority(task, prio))
+    }
+
+    /// Returns the number of tasks queued on `hart_id`.
+    pub fn queue_len(&self, hart_id: usize) -> usize {
+        self.lens[hart_id].load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of tasks queued across all harts.
+    pub fn total_len(&self) -> usize {
+        wrapping_sum((0..SMP).map(|i| self.queue_len(i)))
+    }
+
+    /// Captures the lengths and counters of every hart, without locking.
+    /// All harts read as online.
+    pub fn snapshot(&self) -> SchedSnapshot<SMP> {
+        let harts: [HartSnapshot; SMP] = core::array::from_fn(|i| HartSnapshot {
+            id: i,
+            name: None,
+            len: self.queue_len(i),
+            online: true,
+            #[cfg(feature = "metrics")]
+            stats: self.stats[i].load(),
+        });
+        let total = wrapping_sum(harts.iter().map(|hart| hart.len));
+        SchedSnapshot { harts, total }
+    }
+
+    /// Calls `f` on the queue of `hart_id` under its read lock, which other
+    /// readers share.
+    ///
+    /// The hart cannot pick nor queue tasks meanwhile, and `f` must not
+    /// call back into the scheduler for anything which writes that queue.
+    pub fn with_local<R>(&self, hart_id: usize, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.queues[hart_id].read())
+    }
+
+    /// Calls `f` on every task queued on `hart_id`, under the read lock as
+    /// by [`with_local`](Self::with_local).
+    pub fn for_each_task(&self, hart_id: usize, f: impl FnMut(&S::SchedItem))
+    where
+        S: ForEachTask,
+    {
+        self.with_local(hart_id, |queue| queue.for_each_task(f));
+    }
+}
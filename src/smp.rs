@@ -1,86 +1,3346 @@
-use crate::ScheduleHart;
-use alloc::vec::Vec;
-use core::marker::PhantomData;
-use scheduler::BaseScheduler;
-
-pub struct SmpScheduler<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
-{
-    local_queues: Vec<lock_api::Mutex<L, S>>,
-    hart: PhantomData<H>,
-}
-
-impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
-    SmpScheduler<SMP, S, L, H>
-{
-    /// Creates a new empty [`SmpScheduler`].
-    pub fn new(mut schedulers: Vec<S>) -> Self {
-        assert_eq!(schedulers.len(), SMP);
-        let mut local_queues = Vec::new();
-        for _ in 0..SMP {
-            local_queues.push(lock_api::Mutex::new(schedulers.pop().unwrap()));
-        }
-        Self {
-            local_queues,
-            hart: PhantomData,
-        }
-    }
-}
-
-impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
-    SmpScheduler<SMP, S, L, H>
-{
-    pub fn init(&self) {
-        for i in 0..SMP {
-            self.local_queues[i].lock().init();
-        }
-    }
-
-    pub fn add_task(&self, task: S::SchedItem) {
-        let hart_id = H::hart_id();
-        self.local_queues[hart_id].lock().add_task(task);
-    }
-
-    pub fn remove_task(&self, task: &S::SchedItem) -> Option<S::SchedItem> {
-        let hart_id = H::hart_id();
-        self.local_queues[hart_id].lock().remove_task(task)
-    }
-
-    pub fn pick_next_task(&self) -> Option<S::SchedItem> {
-        let hart_id = H::hart_id();
-        let local = self.local_queues[hart_id].lock().pick_next_task();
-        if local.is_some() {
-            return local;
-        }
-        // steal task from other harts
-        for i in 0..SMP {
-            if i != hart_id {
-                let lock = self.local_queues[i].try_lock();
-                if lock.is_some() {
-                    let mut other = lock.unwrap();
-                    let task = other.pick_next_task();
-                    if task.is_some() {
-                        return task;
-                    }
-                }
-            }
-        }
-        None
-    }
-
-    pub fn put_prev_task(&self, prev: S::SchedItem, preempt: bool) {
-        let hart_id = H::hart_id();
-        self.local_queues[hart_id]
-            .lock()
-            .put_prev_task(prev, preempt);
-    }
-
-    pub fn task_tick(&self, current: &S::SchedItem) -> bool {
-        let hart_id = H::hart_id();
-        self.local_queues[hart_id].lock().task_tick(current)
-    }
-
-    pub fn set_priority(&self, task: &S::SchedItem, prio: isize) -> bool {
-        let hart_id = H::hart_id();
-        self.local_queues[hart_id].lock().set_priority(task, prio)
-    }
-}
+use crate::context::HartContext;
+use crate::hook::{FallbackSource, Parker, StealFilter, TaskSink, WakeHart};
+use crate::replay::ReplayOp;
+#[cfg(feature = "replay")]
+use crate::replay::{ReplayEntry, ReplayLog};
+#[cfg(feature = "metrics")]
+use crate::stats::HartStats;
+use crate::stats::{
+    saturating_sub, wrapping_sum, FairnessReport, HartCounters, HartSnapshot, SchedSnapshot,
+};
+use crate::task::{HasTaskMeta, TaskId};
+use crate::trace::trace_sched;
+use crate::{ScheduleHart, TryReserve};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use scheduler::BaseScheduler;
+
+/// How many times a steal scan is repeated when victims raced empty or were
+/// contended while it ran.
+const STEAL_PASSES: usize = 3;
+
+/// Fraction bits of the fixed-point average queue length.
+const LOAD_SHIFT: u32 = 8;
+
+/// Each tick moves the average queue length `1 / 2^LOAD_DECAY` of the way
+/// towards the current length.
+const LOAD_DECAY: u32 = 3;
+
+/// What `try_add_task` does when the target
+/// queue is at capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// hand the new task back to the caller
+    #[default]
+    Reject,
+    /// discard the oldest queued task to make room for the new one
+    DropOldest,
+    /// discard the new task
+    DropNewest,
+    /// queue the new task on the least loaded hart with room left, and hand
+    /// it back only if every hart is full
+    Spill,
+    /// park the producer with the `Parker` until the queue has room, for
+    /// backpressure in producer/consumer pipelines
+    Block,
+}
+
+/// Where `put_prev_task_ex` puts a task back in the local queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// ahead of every queued task, so it runs next
+    Front,
+    /// behind every queued task
+    Back,
+    /// wherever the inner scheduler ranks it, as for a task which was not
+    /// preempted
+    ByPriority,
+}
+
+/// Which end of a victim's queue thieves take tasks from, see
+/// `set_steal_end`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StealEnd {
+    /// the task the victim would run next
+    #[default]
+    Head,
+    /// the task the victim would run last
+    Tail,
+}
+
+/// Why `try_steal_once` came back empty-handed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StealMiss {
+    /// the victim had nothing queued
+    Empty,
+    /// the victim's queue was locked by someone else
+    Contended,
+    /// the steal filter refused every task of the victim, which keeps them
+    Refused,
+}
+
+/// A broken invariant found by `validate`.
+#[cfg(feature = "debug-checks")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    /// some queue was locked, so the scheduler is not quiescent
+    Busy,
+    /// the length counter of a queue disagrees with what it holds
+    LenMismatch {
+        /// the hart of the queue
+        hart: usize,
+        /// whether it is the urgent lane of the hart
+        urgent: bool,
+        /// the value of the counter
+        counted: usize,
+        /// the number of tasks in the queue
+        actual: usize,
+    },
+    /// an offline hart still holds tasks
+    OfflineNotEmpty {
+        /// the offline hart
+        hart: usize,
+        /// the number of tasks it holds
+        len: usize,
+    },
+}
+
+/// Picks the priority of a task stolen from one hart by another.
+type StealBoost<T> = fn(&T, usize, usize) -> Option<isize>;
+
+/// Tells whether two handles stand for the same task.
+pub type Same<T> = fn(&T, &T) -> bool;
+
+/// Tells whether two handles point to the same task, the default [`Same`].
+pub(crate) fn same_address<T: Deref>(a: &T, b: &T) -> bool {
+    core::ptr::eq(&**a, &**b)
+}
+
+/// Tells whether a task may leave its hart, see `add_task_pinned`.
+type PinCheck<T> = fn(&T) -> bool;
+
+/// Told about a task stolen from one NUMA node by a hart of another.
+type CrossNodeSteal<T> = fn(&T, usize, usize);
+
+/// Returns true if the bit of `hart_id` is set in `mask`.
+fn mask_allows(mask: u64, hart_id: usize) -> bool {
+    hart_id < 64 && mask & (1 << hart_id) != 0
+}
+
+/// Takes every task out of a locked queue, in pick order.
+pub(crate) fn drain_queue<S: BaseScheduler>(queue: &mut S) -> Vec<S::SchedItem> {
+    let mut tasks = Vec::new();
+    while let Some(task) = queue.pick_next_task() {
+        tasks.push(task);
+    }
+    tasks
+}
+
+/// Puts back tasks taken out by [`drain_queue`], in the same order.
+///
+/// They go through the inner `put_prev_task` as if each had just been
+/// descheduled, not through `add_task`, so that a CFS task keeps its
+/// vruntime instead of restarting at the queue minimum. RR tasks get a
+/// full time slice back.
+pub(crate) fn refill_queue<S: BaseScheduler>(queue: &mut S, tasks: Vec<S::SchedItem>) {
+    for task in tasks {
+        queue.put_prev_task(task, false);
+    }
+}
+
+/// The queues locked by `try_lock_all`, indexed by hart id.
+///
+/// They are released in the reverse order they were locked when this is
+/// dropped, as `IrqSafeMutex` needs for its interrupt states.
+pub struct QueueGuards<'a, L: lock_api::RawMutex, S>(Vec<lock_api::MutexGuard<'a, L, S>>);
+
+impl<'a, L: lock_api::RawMutex, S> Deref for QueueGuards<'a, L, S> {
+    type Target = [lock_api::MutexGuard<'a, L, S>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<L: lock_api::RawMutex, S> core::ops::DerefMut for QueueGuards<'_, L, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<L: lock_api::RawMutex, S> Drop for QueueGuards<'_, L, S> {
+    fn drop(&mut self) {
+        while self.0.pop().is_some() {}
+    }
+}
+
+/// A queue kept beside the harts' queues: the run next slot or the urgent
+/// lane of a hart, served before its normal queue, or the global overflow
+/// queue.
+struct SideQueue<L: lock_api::RawMutex, S> {
+    queue: lock_api::Mutex<L, S>,
+    len: AtomicUsize,
+}
+
+/// The lock-free state kept next to each hart's queue.
+pub(crate) struct HartState {
+    /// tasks currently queued on the hart
+    len: AtomicUsize,
+    /// cleared once the hart is scaled away
+    online: AtomicBool,
+    /// set while the hart waits in `wait_for_task`
+    parked: AtomicBool,
+    /// average queue length, fixed point with `LOAD_SHIFT` fraction bits
+    load_avg: AtomicUsize,
+    /// hart last stolen from, `usize::MAX` for none
+    last_victim: AtomicUsize,
+    /// victim the next steal scan of this hart starts at
+    steal_cursor: AtomicUsize,
+    /// consecutive empty polls of `pick_next_task_or_idle`
+    idle_streak: AtomicUsize,
+    /// hart whose full queue this hart waits on in `try_add_task`,
+    /// `usize::MAX` for none
+    blocked_on: AtomicUsize,
+    /// picks are refused once it reaches zero, `usize::MAX` for unbounded
+    budget: AtomicUsize,
+    /// given to `with_hart_names`
+    name: Option<&'static str>,
+    stats: HartCounters,
+}
+
+impl Default for HartState {
+    fn default() -> Self {
+        Self {
+            len: AtomicUsize::new(0),
+            online: AtomicBool::new(true),
+            parked: AtomicBool::new(false),
+            load_avg: AtomicUsize::new(0),
+            last_victim: AtomicUsize::new(usize::MAX),
+            steal_cursor: AtomicUsize::new(0),
+            idle_streak: AtomicUsize::new(0),
+            blocked_on: AtomicUsize::new(usize::MAX),
+            budget: AtomicUsize::new(usize::MAX),
+            name: None,
+            stats: HartCounters::default(),
+        }
+    }
+}
+
+impl HartState {
+    /// Captures the state of hart `id`.
+    pub(crate) fn snapshot(&self, id: usize) -> HartSnapshot {
+        HartSnapshot {
+            id,
+            name: self.name,
+            len: self.len.load(Ordering::Relaxed),
+            online: self.online.load(Ordering::Relaxed),
+            #[cfg(feature = "metrics")]
+            stats: self.stats.load(),
+        }
+    }
+
+    /// Copies the state for a cloned scheduler, which has nobody parked nor
+    /// idling.
+    fn copy(&self) -> Self {
+        let hart = Self {
+            name: self.name,
+            ..Self::default()
+        };
+        hart.len
+            .store(self.len.load(Ordering::Relaxed), Ordering::Relaxed);
+        let online = self.online.load(Ordering::Relaxed);
+        hart.online.store(online, Ordering::Relaxed);
+        hart.load_avg
+            .store(self.load_avg.load(Ordering::Relaxed), Ordering::Relaxed);
+        hart.last_victim
+            .store(self.last_victim.load(Ordering::Relaxed), Ordering::Relaxed);
+        hart.steal_cursor
+            .store(self.steal_cursor.load(Ordering::Relaxed), Ordering::Relaxed);
+        hart.budget
+            .store(self.budget.load(Ordering::Relaxed), Ordering::Relaxed);
+        hart.stats.restore(&self.stats);
+        hart
+    }
+}
+
+pub struct SmpScheduler<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
+{
+    local_queues: Vec<lock_api::Mutex<L, S>>,
+    harts: Arc<[HartState]>,
+    /// the run next slot of every hart, holding one task at most
+    run_next: Vec<SideQueue<L, S>>,
+    #[cfg(feature = "urgent")]
+    urgent: Vec<SideQueue<L, S>>,
+    overflow_queue: Option<SideQueue<L, S>>,
+    steal_limit: Option<usize>,
+    steal_back: Option<(usize, fn() -> usize)>,
+    class_masks: Vec<u64>,
+    capacity: Option<usize>,
+    overflow: OverflowPolicy,
+    steal_end: StealEnd,
+    min_remain: usize,
+    steal_spins: usize,
+    producer_affinity: bool,
+    /// the shared steal epoch, `None` for per-hart cursors
+    steal_epoch: Option<AtomicUsize>,
+    on_empty: fn(usize),
+    on_leak: Option<fn(&[usize])>,
+    should_steal: fn(usize, usize, usize) -> bool,
+    on_steal_fail: fn(usize, usize),
+    steal_boost: Option<StealBoost<S::SchedItem>>,
+    same: Option<Same<S::SchedItem>>,
+    /// matches tasks where no `same` predicate was set
+    identity: Same<S::SchedItem>,
+    /// counts a locked queue, for inner schedulers dropping tasks on their own
+    count: Option<fn(&S) -> usize>,
+    /// the node of every hart, and the hook of `set_on_cross_node_steal`
+    numa: Option<([usize; SMP], CrossNodeSteal<S::SchedItem>)>,
+    parker: Option<Arc<dyn Parker + Send + Sync>>,
+    steal_filter: Option<Arc<dyn StealFilter<S::SchedItem> + Send + Sync>>,
+    /// `is_migratable` of the task type, set when a task is first pinned
+    pin_check: lock_api::Mutex<L, Option<PinCheck<S::SchedItem>>>,
+    /// set once `pin_check` is, so that thieves only lock it after that
+    pinning: AtomicBool,
+    waker: Option<Arc<dyn WakeHart + Send + Sync>>,
+    fallback: Option<Arc<dyn FallbackSource<S::SchedItem> + Send + Sync>>,
+    sink: Option<Arc<dyn TaskSink<S::SchedItem> + Send + Sync>>,
+    #[cfg(feature = "replay")]
+    replay: ReplayLog,
+    #[cfg(any(feature = "replay", feature = "trace"))]
+    task_id: fn(&S::SchedItem) -> usize,
+    /// harts each task family was spread to, see `add_task_spread`
+    families: lock_api::Mutex<L, BTreeMap<usize, [bool; SMP]>>,
+    /// serializes `scale_harts` and `init` calls
+    scaling: lock_api::Mutex<L, ()>,
+    /// set once `init` has run
+    initialized: AtomicBool,
+    /// the simulated hart of `pick_next_task_cooperative`
+    #[cfg(any(test, feature = "sim"))]
+    sim_cursor: AtomicUsize,
+    /// the entry of the scheduler in the live registry
+    #[cfg(feature = "smp-debug")]
+    debug_id: usize,
+    hart: PhantomData<H>,
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
+    SmpScheduler<SMP, S, L, H>
+{
+    /// The number of harts, usable in const contexts.
+    pub const NR_HARTS: usize = SMP;
+
+    /// Creates a new empty [`SmpScheduler`], matching tasks by address.
+    pub fn new(schedulers: Vec<S>) -> Self
+    where
+        S::SchedItem: Deref,
+    {
+        Self::new_named(schedulers, [None; SMP])
+    }
+
+    /// Creates a new empty [`SmpScheduler`] whose harts are named for
+    /// diagnostics, `names[i]` being the name of hart `i`, say on a SoC
+    /// whose harts are not all alike. The names show up in
+    /// [snapshots](Self::snapshot), and in the
+    /// [live registry](crate::for_each_scheduler) with the `smp-debug`
+    /// feature. Without names diagnostics only have the hart indices.
+    pub fn with_hart_names(schedulers: Vec<S>, names: [&'static str; SMP]) -> Self
+    where
+        S::SchedItem: Deref,
+    {
+        Self::new_named(schedulers, names.map(Some))
+    }
+
+    fn new_named(mut schedulers: Vec<S>, names: [Option<&'static str>; SMP]) -> Self
+    where
+        S::SchedItem: Deref,
+    {
+        assert_eq!(schedulers.len(), SMP);
+        let mut local_queues = Vec::new();
+        let mut harts = Vec::new();
+        for name in names {
+            local_queues.push(lock_api::Mutex::new(schedulers.pop().unwrap()));
+            harts.push(HartState {
+                name,
+                ..HartState::default()
+            });
+        }
+        let harts: Arc<[HartState]> = harts.into();
+        Self {
+            local_queues,
+            #[cfg(feature = "smp-debug")]
+            debug_id: crate::debug::register(core::any::type_name::<S>(), harts.clone()),
+            harts,
+            run_next: Vec::new(),
+            #[cfg(feature = "urgent")]
+            urgent: Vec::new(),
+            overflow_queue: None,
+            steal_limit: None,
+            steal_back: None,
+            class_masks: Vec::new(),
+            capacity: None,
+            overflow: OverflowPolicy::Reject,
+            steal_end: StealEnd::Head,
+            min_remain: 0,
+            steal_spins: 0,
+            producer_affinity: false,
+            steal_epoch: None,
+            on_empty: |_| {},
+            on_leak: None,
+            should_steal: |_, _, _| true,
+            on_steal_fail: |_, _| {},
+            steal_boost: None,
+            same: None,
+            identity: same_address,
+            count: None,
+            numa: None,
+            parker: None,
+            steal_filter: None,
+            pin_check: lock_api::Mutex::new(None),
+            pinning: AtomicBool::new(false),
+            waker: None,
+            fallback: None,
+            sink: None,
+            #[cfg(feature = "replay")]
+            replay: ReplayLog::new(),
+            #[cfg(any(feature = "replay", feature = "trace"))]
+            task_id: |_| 0,
+            families: lock_api::Mutex::new(BTreeMap::new()),
+            scaling: lock_api::Mutex::new(()),
+            initialized: AtomicBool::new(false),
+            #[cfg(any(test, feature = "sim"))]
+            sim_cursor: AtomicUsize::new(0),
+            hart: PhantomData,
+        }
+    }
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart> Drop
+    for SmpScheduler<SMP, S, L, H>
+{
+    fn drop(&mut self) {
+        #[cfg(feature = "smp-debug")]
+        crate::debug::unregister(self.debug_id);
+        // a panicking hook would abort the unwinding; without `std` there is
+        // no telling, and the hooks run even while unwinding
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            return;
+        }
+        if let Some(on_leak) = self.on_leak {
+            // only the counters are read
+            let lens: [usize; SMP] = core::array::from_fn(|i| {
+                #[cfg(feature = "urgent")]
+                let urgent = self.urgent_len(i);
+                #[cfg(not(feature = "urgent"))]
+                let urgent = 0;
+                self.queue_len(i) + urgent + self.run_next_len(i)
+            });
+            if lens.iter().any(|&len| len > 0) {
+                on_leak(&lens);
+            }
+        }
+        if let Some(sink) = self.sink.take() {
+            // no lock is taken, so a lock left held, say by a forgotten
+            // guard, cannot hang the drop
+            let recycle = |queue: &mut S| {
+                while let Some(task) = queue.pick_next_task() {
+                    sink.recycle(task);
+                }
+            };
+            let sides = self.run_next.iter_mut();
+            #[cfg(feature = "urgent")]
+            let sides = sides.chain(self.urgent.iter_mut());
+            for side in sides.chain(self.overflow_queue.as_mut()) {
+                recycle(side.queue.get_mut());
+            }
+            for queue in self.local_queues.iter_mut() {
+                recycle(queue.get_mut());
+            }
+        }
+    }
+}
+
+/// Cloning locks the harts one after another, so under concurrency the copy
+/// is not a globally consistent snapshot of the scheduler.
+impl<const SMP: usize, S: BaseScheduler + Clone, L: lock_api::RawMutex, H: ScheduleHart> Clone
+    for SmpScheduler<SMP, S, L, H>
+{
+    fn clone(&self) -> Self {
+        let mut local_queues = Vec::new();
+        let mut harts = Vec::new();
+        for i in 0..SMP {
+            let queue = self.local_queues[i].lock();
+            local_queues.push(lock_api::Mutex::new(queue.clone()));
+            harts.push(self.harts[i].copy());
+        }
+        let harts: Arc<[HartState]> = harts.into();
+        Self {
+            local_queues,
+            #[cfg(feature = "smp-debug")]
+            debug_id: crate::debug::register(core::any::type_name::<S>(), harts.clone()),
+            harts,
+            run_next: self
+                .run_next
+                .iter()
+                .map(|slot| SideQueue {
+                    queue: lock_api::Mutex::new(slot.queue.lock().clone()),
+                    len: AtomicUsize::new(slot.len.load(Ordering::Relaxed)),
+                })
+                .collect(),
+            #[cfg(feature = "urgent")]
+            urgent: self
+                .urgent
+                .iter()
+                .map(|lane| SideQueue {
+                    queue: lock_api::Mutex::new(lane.queue.lock().clone()),
+                    len: AtomicUsize::new(lane.len.load(Ordering::Relaxed)),
+                })
+                .collect(),
+            overflow_queue: self.overflow_queue.as_ref().map(|overflow| SideQueue {
+                queue: lock_api::Mutex::new(overflow.queue.lock().clone()),
+                len: AtomicUsize::new(overflow.len.load(Ordering::Relaxed)),
+            }),
+            steal_limit: self.steal_limit,
+            steal_back: self.steal_back,
+            class_masks: self.class_masks.clone(),
+            capacity: self.capacity,
+            overflow: self.overflow,
+            steal_end: self.steal_end,
+            min_remain: self.min_remain,
+            steal_spins: self.steal_spins,
+            producer_affinity: self.producer_affinity,
+            steal_epoch: self
+                .steal_epoch
+                .as_ref()
+                .map(|epoch| AtomicUsize::new(epoch.load(Ordering::Relaxed))),
+            on_empty: self.on_empty,
+            on_leak: self.on_leak,
+            should_steal: self.should_steal,
+            on_steal_fail: self.on_steal_fail,
+            steal_boost: self.steal_boost,
+            same: self.same,
+            identity: self.identity,
+            count: self.count,
+            numa: self.numa,
+            parker: self.parker.clone(),
+            steal_filter: self.steal_filter.clone(),
+            pin_check: lock_api::Mutex::new(*self.pin_check.lock()),
+            pinning: AtomicBool::new(self.pinning.load(Ordering::Acquire)),
+            waker: self.waker.clone(),
+            fallback: self.fallback.clone(),
+            sink: self.sink.clone(),
+            #[cfg(feature = "replay")]
+            replay: ReplayLog::new(),
+            #[cfg(any(feature = "replay", feature = "trace"))]
+            task_id: self.task_id,
+            families: lock_api::Mutex::new(self.families.lock().clone()),
+            scaling: lock_api::Mutex::new(()),
+            initialized: AtomicBool::new(self.initialized.load(Ordering::Relaxed)),
+            #[cfg(any(test, feature = "sim"))]
+            sim_cursor: AtomicUsize::new(self.sim_cursor.load(Ordering::Relaxed)),
+            hart: PhantomData,
+        }
+    }
+}
+
+impl<const SMP: usize, S: BaseScheduler, L: lock_api::RawMutex, H: ScheduleHart>
+    SmpScheduler<SMP, S, L, H>
+{
+    /// Initializes the inner schedulers, which must happen before any task
+    /// is added.
+    ///
+    /// Only the first call does anything: later ones return right away
+    /// rather than re-initializing queues which may already hold work. Use
+    /// [`reinit`](Self::reinit) to reset a hart on purpose. Concurrent calls
+    /// all return once the scheduler is initialized.
+    pub fn init(&self) {
+        if self.initialized.load(Ordering::Acquire) {
+            return;
+        }
+        let _guard = self.scaling.lock();
+        if self.initialized.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(count) = H::hart_count() {
+            assert_eq!(
+                count, SMP,
+                "ScheduleHart reports {count} harts but the scheduler was built for {SMP}"
+            );
+        }
+        for i in 0..SMP {
+            self.local_queues[i].lock().init();
+        }
+        for slot in self.run_next.iter() {
+            slot.queue.lock().init();
+        }
+        #[cfg(feature = "urgent")]
+        for lane in self.urgent.iter() {
+            lane.queue.lock().init();
+        }
+        if let Some(overflow) = &self.overflow_queue {
+            overflow.queue.lock().init();
+        }
+        self.initialized.store(true, Ordering::Release);
+    }
+
+    /// Panics unless [`init`](Self::init) has been called.
+    #[inline]
+    fn check_init(&self) {
+        assert!(
+            self.initialized.load(Ordering::Relaxed),
+            "SmpScheduler used before init(): call init() first"
+        );
+    }
+
+    pub fn add_task(&self, task: S::SchedItem) {
+        self.add_task_on(H::hart_id(), task);
+    }
+
+    /// Adds a task to the queue of `hart_id` instead of the current hart.
+    ///
+    /// Work never lands on an offline hart: if `hart_id` is offline the task
+    /// goes to the least loaded online hart instead. See
+    /// [`try_add_task_on`](Self::try_add_task_on) to get it back instead.
+    pub fn add_task_on(&self, hart_id: usize, task: S::SchedItem) {
+        self.add_task_as(H::hart_id(), hart_id, task);
+    }
+
+    /// Adds a task at the head of the current hart's queue rather than where
+    /// the inner scheduler would rank it, by draining and refilling the
+    /// queue under its lock. See `enqueue_front` of the FIFO wrapper.
+    #[cfg(feature = "fifo")]
+    pub(crate) fn add_task_front(&self, task: S::SchedItem) {
+        let current = H::hart_id();
+        let hart_id = self.online_or_least_loaded(current);
+        let mut queue = self.local_queues[hart_id].lock();
+        let tasks = drain_queue(&mut *queue);
+        self.enqueue_locked(hart_id, &mut queue, task);
+        refill_queue(&mut *queue, tasks);
+        self.sync_len(hart_id, &queue);
+        drop(queue);
+        self.notify_as(current, hart_id);
+    }
+
+    /// Adds a task to `hart_id` on behalf of the hart `current`.
+    pub(crate) fn add_task_as(&self, current: usize, hart_id: usize, task: S::SchedItem) {
+        let hart_id = self.online_or_least_loaded(hart_id);
+        let mut queue = self.local_queues[hart_id].lock();
+        self.enqueue_locked(hart_id, &mut queue, task);
+        drop(queue);
+        self.notify_as(current, hart_id);
+    }
+
+    /// Like [`add_task`](Self::add_task), but reserves room in the queue
+    /// first and hands the task back if that allocation fails, rather than
+    /// aborting on out of memory.
+    pub fn add_task_fallible(&self, task: S::SchedItem) -> Result<(), S::SchedItem>
+    where
+        S: TryReserve,
+    {
+        let current = H::hart_id();
+        let hart_id = self.online_or_least_loaded(current);
+        let mut queue = self.local_queues[hart_id].lock();
+        if queue.try_reserve_for(&task).is_err() {
+            return Err(task);
+        }
+        self.enqueue_locked(hart_id, &mut queue, task);
+        drop(queue);
+        self.notify_as(current, hart_id);
+        Ok(())
+    }
+
+    /// Adds a task to the queue of `hart_id`, handing it back if that hart is
+    /// offline.
+    pub fn try_add_task_on(&self, hart_id: usize, task: S::SchedItem) -> Result<(), S::SchedItem> {
+        if !self.is_hart_online(hart_id) {
+            return Err(task);
+        }
+        self.add_task_on(hart_id, task);
+        Ok(())
+    }
+
+    /// Returns whether `hart_id` is online, see
+    /// [`scale_harts`](Self::scale_harts). Entry points which queue work on a
+    /// given hart either redirect it or refuse it when this is false.
+    pub fn is_hart_online(&self, hart_id: usize) -> bool {
+        self.harts[hart_id].online.load(Ordering::Relaxed)
+    }
+
+    /// Returns `hart_id` if it is online, or else the least loaded online
+    /// hart.
+    fn online_or_least_loaded(&self, hart_id: usize) -> usize {
+        if self.is_hart_online(hart_id) {
+            return hart_id;
+        }
+        self.least_loaded(&[false; SMP]).unwrap_or(hart_id)
+    }
+
+    /// Adds a task to the current hart, honouring the
+    /// [capacity](Self::set_capacity) of the queues.
+    ///
+    /// When the queue is full the configured [`OverflowPolicy`] decides what
+    /// happens; the task is handed back as `Err` when it could not be queued
+    /// and was not discarded either.
+    ///
+    /// With [`OverflowPolicy::Block`] the current hart is parked with the
+    /// [`Parker`](crate::Parker) until a pick or a steal makes room, and the
+    /// task is always queued. The full queue is the current hart's own, so
+    /// only thieves can drain it meanwhile: if no other hart steals from
+    /// it, say because the [filter](Self::set_steal_filter) refuses its
+    /// tasks or every other hart is blocked too, the producer deadlocks.
+    /// Panics if no parker was set. Without a capacity this is
+    /// [`add_task`](Self::add_task), which always ignores the capacity.
+    pub fn try_add_task(&self, task: S::SchedItem) -> Result<(), S::SchedItem> {
+        let hart_id = self.online_or_least_loaded(H::hart_id());
+        let Some(capacity) = self.capacity else {
+            self.add_task_on(hart_id, task);
+            return Ok(());
+        };
+        let mut queue = self.local_queues[hart_id].lock();
+        if self.queue_len(hart_id) < capacity {
+            self.enqueue_locked(hart_id, &mut queue, task);
+            drop(queue);
+            self.notify(hart_id);
+            return Ok(());
+        }
+        match self.overflow {
+            OverflowPolicy::Reject => Err(task),
+            OverflowPolicy::DropNewest => {
+                drop(queue);
+                self.discard(task);
+                Ok(())
+            }
+            OverflowPolicy::DropOldest => {
+                let oldest = queue.pick_next_task();
+                if oldest.is_some() {
+                    self.shrink_len(hart_id, &queue, 1);
+                }
+                self.enqueue_locked(hart_id, &mut queue, task);
+                drop(queue);
+                if let Some(oldest) = oldest {
+                    self.discard(oldest);
+                }
+                Ok(())
+            }
+            OverflowPolicy::Spill => {
+                drop(queue);
+                self.spill(hart_id, capacity, task)
+            }
+            OverflowPolicy::Block => {
+                drop(queue);
+                self.add_blocking(hart_id, capacity, task);
+                Ok(())
+            }
+        }
+    }
+
+    /// Queues `task` on `hart_id` once it holds fewer than `capacity` tasks,
+    /// parking the current hart until then.
+    fn add_blocking(&self, hart_id: usize, capacity: usize, task: S::SchedItem) {
+        let current = H::hart_id();
+        let parker = self
+            .parker
+            .as_ref()
+            .expect("OverflowPolicy::Block needs a Parker, see set_parker()");
+        let blocked_on = &self.harts[current].blocked_on;
+        loop {
+            let mut queue = self.local_queues[hart_id].lock();
+            if self.queue_len(hart_id) < capacity {
+                self.enqueue_locked(hart_id, &mut queue, task);
+                drop(queue);
+                self.notify_as(current, hart_id);
+                return;
+            }
+            drop(queue);
+            blocked_on.store(hart_id, Ordering::SeqCst);
+            // pairs with the fence in shrunk()
+            fence(Ordering::SeqCst);
+            if self.queue_len(hart_id) >= capacity {
+                parker.park(current);
+            }
+            blocked_on.store(usize::MAX, Ordering::SeqCst);
+        }
+    }
+
+    /// Queues `task` on the least loaded online hart other than `hart_id`
+    /// which has room left, or hands it back if there is none.
+    fn spill(
+        &self,
+        hart_id: usize,
+        capacity: usize,
+        task: S::SchedItem,
+    ) -> Result<(), S::SchedItem> {
+        let mut tried = [false; SMP];
+        tried[hart_id] = true;
+        loop {
+            let Some(target) = self
+                .least_loaded(&tried)
+                .filter(|&i| self.queue_len(i) < capacity)
+            else {
+                return Err(task);
+            };
+            let mut queue = self.local_queues[target].lock();
+            // the counter may have moved before we got the lock
+            if self.queue_len(target) < capacity {
+                self.enqueue_locked(target, &mut queue, task);
+                drop(queue);
+                self.notify(target);
+                return Ok(());
+            }
+            tried[target] = true;
+        }
+    }
+
+    /// Returns the online hart with the shortest queue, leaving out the harts
+    /// set in `skip`.
+    ///
+    /// All queues share one capacity, so when the result is full every
+    /// candidate is.
+    fn least_loaded(&self, skip: &[bool; SMP]) -> Option<usize> {
+        self.least_loaded_near(skip, None)
+    }
+
+    /// Like `least_loaded`, but breaks ties in favour of `last_hart`, then of
+    /// the current hart, then of the lowest index, whose caches are the most
+    /// likely to be warm for the task in that order.
+    fn least_loaded_near(&self, skip: &[bool; SMP], last_hart: Option<usize>) -> Option<usize> {
+        let current = H::hart_id();
+        (0..SMP)
+            .filter(|&i| !skip[i] && self.harts[i].online.load(Ordering::Relaxed))
+            .min_by_key(|&i| (self.queue_len(i), Some(i) != last_hart, i != current))
+    }
+
+    /// Adds a task to the online hart with the shortest queue, rather than
+    /// to the current hart. Ties go to the current hart, then to the lowest
+    /// index.
+    ///
+    /// The lengths are read without locking, so concurrent callers may pick
+    /// the same hart. Like [`add_task`](Self::add_task) this ignores the
+    /// [capacity](Self::set_capacity).
+    pub fn balanced_add(&self, task: S::SchedItem) {
+        let hart_id = self.least_loaded(&[false; SMP]).unwrap_or_else(H::hart_id);
+        self.add_task_on(hart_id, task);
+    }
+
+    /// Like [`balanced_add`](Self::balanced_add), but ties go to the hart
+    /// which last picked the task, as recorded in its
+    /// [`TaskMeta`](crate::TaskMeta), before the current hart.
+    pub fn balanced_add_tracked(&self, task: S::SchedItem)
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        let last_hart = task.task_meta().last_hart();
+        let hart_id = self
+            .least_loaded_near(&[false; SMP], last_hart)
+            .unwrap_or_else(H::hart_id);
+        self.add_task_on(hart_id, task);
+    }
+
+    /// Adds a member of the task family `family_id`, keeping the family
+    /// spread over different harts so that related tasks do not contend on
+    /// the same one.
+    ///
+    /// The task goes to the least loaded online hart which was not given a
+    /// member of the family yet, or to the least loaded online hart, as by
+    /// [`balanced_add`](Self::balanced_add), once they all were. Membership
+    /// is recorded by placement: the scheduler cannot tell when a member
+    /// finishes, so a hart keeps counting as used until
+    /// [`clear_family`](Self::clear_family) is called.
+    pub fn add_task_spread(&self, family_id: usize, task: S::SchedItem) {
+        let mut families = self.families.lock();
+        let used = families.entry(family_id).or_insert([false; SMP]);
+        let hart_id = self
+            .least_loaded(used)
+            .or_else(|| self.least_loaded(&[false; SMP]))
+            .unwrap_or_else(H::hart_id);
+        used[hart_id] = true;
+        drop(families);
+        self.add_task_on(hart_id, task);
+    }
+
+    /// Forgets where the members of `family_id` were placed.
+    pub fn clear_family(&self, family_id: usize) {
+        self.families.lock().remove(&family_id);
+    }
+
+    /// Sets the number of tasks each queue may hold for
+    /// [`try_add_task`](Self::try_add_task), `None` (the default) meaning
+    /// unbounded, and what to do when a queue is full.
+    pub fn set_capacity(&mut self, capacity: Option<usize>, overflow: OverflowPolicy) {
+        self.capacity = capacity;
+        self.overflow = overflow;
+    }
+
+    /// Queues `task` on `hart_id`, whose queue is locked by the caller.
+    fn enqueue_locked(&self, hart_id: usize, queue: &mut S, task: S::SchedItem) {
+        self.check_init();
+        self.log(hart_id, ReplayOp::Add, &task);
+        queue.add_task(task);
+        self.grow_len(hart_id, 1);
+        self.harts[hart_id].stats.record_enqueue();
+    }
+
+    pub fn remove_task(&self, task: &S::SchedItem) -> Option<S::SchedItem> {
+        self.remove_task_on(H::hart_id(), task)
+    }
+
+    pub(crate) fn remove_task_on(
+        &self,
+        hart_id: usize,
+        task: &S::SchedItem,
+    ) -> Option<S::SchedItem> {
+        let mut queue = self.local_queues[hart_id].lock();
+        let Some(task) = self.remove_matching(&mut queue, task) else {
+            self.sync_len(hart_id, &queue);
+            return None;
+        };
+        let emptied = self.shrink_len(hart_id, &queue, 1);
+        drop(queue);
+        self.shrunk(hart_id, emptied);
+        Some(task)
+    }
+
+    /// Sets the predicate telling whether two handles stand for the same
+    /// task, used for all matching: [`remove_task`](Self::remove_task),
+    /// [`remove_tasks`](Self::remove_tasks),
+    /// [`contains_task`](Self::contains_task) and
+    /// [`migrate_task`](Self::migrate_task). This suits task types which
+    /// implement neither `PartialEq` nor [`TaskId`](crate::TaskId). The
+    /// wrappers also take it at construction, as by `new_with_same`.
+    ///
+    /// Without one (the default) tasks are matched by address, the `Arc`
+    /// pointer equality. Either way a queue is searched by draining and
+    /// refilling it, as by [`remove_task_by_id`](Self::remove_task_by_id),
+    /// rather than with the inner `remove_task`: CFS looks tasks up by their
+    /// (vruntime, id) key, and as ids are only unique within a queue, it
+    /// would take out a different task which happened to share the key.
+    pub fn set_same(&mut self, same: Same<S::SchedItem>) {
+        self.same = Some(same);
+    }
+
+    /// Sets how to count the tasks of a locked queue, for inner schedulers
+    /// which drop tasks on their own, such as the
+    /// [`WeakFifoScheduler`](crate::WeakFifoScheduler). The length counters
+    /// are then reset to the count whenever tasks leave a queue or it was
+    /// drained, rather than decremented by the tasks seen to leave.
+    #[cfg(feature = "weak")]
+    pub(crate) fn set_count(&mut self, count: fn(&S) -> usize) {
+        self.count = Some(count);
+    }
+
+    /// Removes `task` from a locked queue, matched as set by
+    /// [`set_same`](Self::set_same).
+    fn remove_matching(&self, queue: &mut S, task: &S::SchedItem) -> Option<S::SchedItem> {
+        let same = self.same.unwrap_or(self.identity);
+        let mut tasks = drain_queue(queue);
+        let found = tasks.iter().position(|queued| same(queued, task));
+        let task = found.map(|i| tasks.remove(i));
+        refill_queue(queue, tasks);
+        task
+    }
+
+    /// Returns the hart `task` is queued on, matched with the
+    /// [predicate](Self::set_same), or by address if none was set.
+    ///
+    /// The inner schedulers cannot search their queues without removing from
+    /// them, so every queue is drained and refilled in order under its lock,
+    /// which leaves the pick order and the CFS vruntimes as they were.
+    pub fn contains_task(&self, task: &S::SchedItem) -> Option<usize> {
+        let same = self.same.unwrap_or(self.identity);
+        (0..SMP).find(|&hart_id| {
+            let mut queue = self.local_queues[hart_id].lock();
+            let tasks = drain_queue(&mut *queue);
+            let found = tasks.iter().any(|queued| same(queued, task));
+            refill_queue(&mut *queue, tasks);
+            self.sync_len(hart_id, &queue);
+            found
+        })
+    }
+
+    /// Moves `task` to the queue of `to`, from whichever hart it is queued
+    /// on, and returns whether it was found. It is matched as by
+    /// [`remove_task`](Self::remove_task), so only ever taken off the hart
+    /// actually holding it, and added as by
+    /// [`add_task_on`](Self::add_task_on).
+    pub fn migrate_task(&self, task: &S::SchedItem, to: usize) -> bool {
+        match (0..SMP).find_map(|hart_id| self.remove_task_on(hart_id, task)) {
+            Some(task) => {
+                self.add_task_on(to, task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every task of `tasks` from whichever hart it is queued on,
+    /// returning how many were found.
+    ///
+    /// Each hart is locked, drained and refilled once for the whole batch.
+    /// Tasks are matched the same way as by
+    /// [`remove_task`](Self::remove_task), with the
+    /// [predicate](Self::set_same) if one was set and by address otherwise.
+    pub fn remove_tasks(&self, tasks: &[S::SchedItem]) -> usize {
+        let same = self.same.unwrap_or(self.identity);
+        let mut found = alloc::vec![false; tasks.len()];
+        let mut removed = 0;
+        for hart_id in 0..SMP {
+            if removed == tasks.len() {
+                break;
+            }
+            let mut queue = self.local_queues[hart_id].lock();
+            let mut queued = drain_queue(&mut *queue);
+            let mut here = 0;
+            for (task, found) in tasks.iter().zip(found.iter_mut()) {
+                if let Some(i) = queued
+                    .iter()
+                    .position(|queued| !*found && same(queued, task))
+                {
+                    queued.remove(i);
+                    *found = true;
+                    here += 1;
+                }
+            }
+            refill_queue(&mut *queue, queued);
+            let emptied = self.shrink_len(hart_id, &queue, here);
+            drop(queue);
+            self.shrunk(hart_id, emptied);
+            removed += here;
+        }
+        removed
+    }
+
+    /// Removes the task with the given [`TaskId`](crate::TaskId) from
+    /// whichever hart it is queued on.
+    ///
+    /// [`remove_task`](Self::remove_task) leaves the matching to the inner
+    /// scheduler, which for the bundled ones means by address. Matching by
+    /// id instead finds the task through any handle, but the inner
+    /// schedulers cannot be searched in place: each queue is drained and
+    /// refilled in order under its lock, which is O(length), keeps the
+    /// vruntimes of CFS tasks but refills the time slices of RR ones.
+    pub fn remove_task_by_id(&self, id: u64) -> Option<S::SchedItem>
+    where
+        S::SchedItem: TaskId,
+    {
+        (0..SMP).find_map(|hart_id| self.remove_id_on(hart_id, id))
+    }
+
+    /// Returns the hart the task with the given id is queued on, searched
+    /// as by [`remove_task_by_id`](Self::remove_task_by_id).
+    pub fn contains_task_id(&self, id: u64) -> Option<usize>
+    where
+        S::SchedItem: TaskId,
+    {
+        (0..SMP).find(|&hart_id| {
+            let mut queue = self.local_queues[hart_id].lock();
+            let tasks = drain_queue(&mut *queue);
+            let found = tasks.iter().any(|task| task.id() == id);
+            refill_queue(&mut *queue, tasks);
+            self.sync_len(hart_id, &queue);
+            found
+        })
+    }
+
+    /// Returns the ids of the tasks queued on `hart_id`, in pick order.
+    ///
+    /// The queue is locked for the whole walk, so unlike
+    /// [`snapshot`](Self::snapshot) this is a consistent view of the hart,
+    /// e.g. for tests asserting the order left by an operation. It is
+    /// drained and refilled as by
+    /// [`remove_task_by_id`](Self::remove_task_by_id): the order and the
+    /// CFS vruntimes are left as they were, so taking a snapshot does not
+    /// change the next picks.
+    pub fn hart_queue_snapshot(&self, hart_id: usize) -> Vec<u64>
+    where
+        S::SchedItem: TaskId,
+    {
+        let mut queue = self.local_queues[hart_id].lock();
+        let tasks = drain_queue(&mut *queue);
+        let ids = tasks.iter().map(|task| task.id()).collect();
+        refill_queue(&mut *queue, tasks);
+        self.sync_len(hart_id, &queue);
+        ids
+    }
+
+    /// Moves the task with the given id to the queue of `to`, wherever it is
+    /// queued, and returns whether it was found. The task is searched as by
+    /// [`remove_task_by_id`](Self::remove_task_by_id) and added as by
+    /// [`add_task_on`](Self::add_task_on).
+    pub fn migrate_task_by_id(&self, id: u64, to: usize) -> bool
+    where
+        S::SchedItem: TaskId,
+    {
+        match self.remove_task_by_id(id) {
+            Some(task) => {
+                self.add_task_on(to, task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_id_on(&self, hart_id: usize, id: u64) -> Option<S::SchedItem>
+    where
+        S::SchedItem: TaskId,
+    {
+        let mut queue = self.local_queues[hart_id].lock();
+        let mut tasks = drain_queue(&mut *queue);
+        let found = tasks.iter().position(|task| task.id() == id);
+        let task = found.map(|i| tasks.remove(i));
+        refill_queue(&mut *queue, tasks);
+        self.sync_len(hart_id, &queue);
+        let task = task?;
+        let emptied = self.shrink_len(hart_id, &queue, 1);
+        drop(queue);
+        self.shrunk(hart_id, emptied);
+        Some(task)
+    }
+
+    /// Picks the next task of the current hart, stealing one from another
+    /// hart when the local queue is empty.
+    ///
+    /// The owner and the thieves all take the task a queue would run next
+    /// (its head, for FIFO), so every queue is drained in its own order: a
+    /// task can only be overtaken by tasks its queue ranks before it, and is
+    /// therefore picked eventually as long as harts keep picking, whether the
+    /// owner keeps enqueueing or not.
+    ///
+    /// Returns `None` without looking at the queues while the hart's
+    /// [budget](Self::budget_remaining) is exhausted.
+    pub fn pick_next_task(&self) -> Option<S::SchedItem> {
+        self.pick_next_task_on(H::hart_id())
+    }
+
+    /// Like [`pick_next_task`](Self::pick_next_task), but gives up stealing
+    /// and returns `None` once `now()` is past `deadline`, even with victims
+    /// left to probe, to bound the time a real-time hart spends looking for
+    /// work. The clock is read before every victim probe, in whatever unit
+    /// `now` counts.
+    ///
+    /// The local queue is always looked at, and the
+    /// [fallback source](Self::set_fallback) only asked if the deadline has
+    /// not passed once stealing failed.
+    pub fn pick_next_task_deadline(
+        &self,
+        deadline: u64,
+        now: impl Fn() -> u64,
+    ) -> Option<S::SchedItem> {
+        self.pick_with(H::hart_id(), |_, _| true, || now() > deadline, |_, _| {})
+    }
+
+    /// Returns what is left of the budget of `hart_id`, e.g. the cycles
+    /// remaining in its scheduling window under a fixed-quantum supervisor.
+    /// Picks on the hart return `None` while it reads zero, so the hart
+    /// yields back to the supervisor.
+    ///
+    /// The scheduler never spends the budget itself: the caller
+    /// [charges](Self::charge_budget) it as work runs. Until first
+    /// [replenished](Self::replenish_budget) it is `usize::MAX`, in effect
+    /// unbounded.
+    pub fn budget_remaining(&self, hart_id: usize) -> usize {
+        self.harts[hart_id].budget.load(Ordering::Relaxed)
+    }
+
+    /// Takes `amount` off the budget of `hart_id`, stopping at zero.
+    pub fn charge_budget(&self, hart_id: usize, amount: usize) {
+        saturating_sub(&self.harts[hart_id].budget, amount);
+    }
+
+    /// Sets the budget of `hart_id` to `amount`, e.g. at the start of its
+    /// next scheduling window.
+    pub fn replenish_budget(&self, hart_id: usize, amount: usize) {
+        self.harts[hart_id].budget.store(amount, Ordering::Relaxed);
+    }
+
+    /// Like [`pick_next_task`](Self::pick_next_task), but an empty poll
+    /// returns how many polls of the current hart came up empty in a row,
+    /// this one included, so that the caller can escalate from spinning to
+    /// waiting for an interrupt.
+    ///
+    /// The streak is reset by any successful pick of the hart through
+    /// `pick_next_task` and friends.
+    pub fn pick_next_task_or_idle(&self) -> Result<S::SchedItem, usize> {
+        let hart_id = H::hart_id();
+        match self.pick_next_task_on(hart_id) {
+            Some(task) => Ok(task),
+            None => {
+                let streak = &self.harts[hart_id].idle_streak;
+                Err(streak.fetch_add(1, Ordering::Relaxed).wrapping_add(1))
+            }
+        }
+    }
+
+    /// Picks the next task as if running on `hart_id`, bypassing
+    /// [`ScheduleHart::hart_id`].
+    ///
+    /// This is a testing and simulation aid which lets a single thread drive
+    /// several harts deterministically. Real harts should use
+    /// [`pick_next_task`](Self::pick_next_task).
+    #[cfg(any(test, feature = "sim"))]
+    pub fn pick_next_task_as(&self, hart_id: usize) -> Option<S::SchedItem> {
+        self.pick_next_task_on(hart_id)
+    }
+
+    /// Picks the next task for a cooperative simulation of all the harts on
+    /// a single thread, returning it along with the simulated hart which
+    /// picked it.
+    ///
+    /// The pick is made as the current simulated hart, which starts out as
+    /// hart 0. It keeps the turn while it picks from its own queues. Once it
+    /// has to steal, it yields to the next hart after taking the task, so
+    /// that successive steals are made by the harts in turn rather than all
+    /// by the first idle one. When it finds nothing, not even to steal, it
+    /// yields as well and the pick is retried on the next hart, until every
+    /// hart had a go. [`ScheduleHart::hart_id`] is never consulted, so this
+    /// must not be mixed with real harts.
+    #[cfg(any(test, feature = "sim"))]
+    pub fn pick_next_task_cooperative(&self) -> Option<(usize, S::SchedItem)> {
+        for _ in 0..SMP {
+            let hart_id = self.sim_cursor.load(Ordering::Relaxed);
+            let mut stolen = false;
+            let task = self.pick_with(
+                hart_id,
+                |_, _| true,
+                || false,
+                |_, from| {
+                    stolen = from.is_some();
+                },
+            );
+            if task.is_none() || stolen {
+                self.sim_cursor
+                    .store((hart_id + 1) % SMP, Ordering::Relaxed);
+            }
+            if let Some(task) = task {
+                return Some((hart_id, task));
+            }
+        }
+        None
+    }
+
+    pub(crate) fn pick_next_task_on(&self, hart_id: usize) -> Option<S::SchedItem> {
+        self.pick_with(hart_id, |_, _| true, || false, |_, _| {})
+    }
+
+    /// The pick pipeline behind `pick_next_task` and its variants: thieves
+    /// take only tasks `allow` accepts (given the task and the victim) and
+    /// give up once `expired` returns true, and `picked` is called with the
+    /// task picked and the hart it was stolen from, if it was.
+    fn pick_with(
+        &self,
+        hart_id: usize,
+        allow: impl FnMut(&S::SchedItem, usize) -> bool,
+        expired: impl FnMut() -> bool,
+        mut picked: impl FnMut(&S::SchedItem, Option<usize>),
+    ) -> Option<S::SchedItem> {
+        if self.budget_remaining(hart_id) == 0 {
+            return None;
+        }
+        let (task, from) = self.find_next_task(hart_id, allow, expired)?;
+        self.end_idle_streak(hart_id);
+        picked(&task, from);
+        Some(task)
+    }
+
+    /// Resets the streak of empty polls of `hart_id` after a successful pick.
+    fn end_idle_streak(&self, hart_id: usize) {
+        let streak = &self.harts[hart_id].idle_streak;
+        if streak.load(Ordering::Relaxed) != 0 {
+            streak.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Finds a task for `hart_id`, along with the hart it was stolen from if
+    /// it was, see [`pick_with`](Self::pick_with).
+    fn find_next_task(
+        &self,
+        hart_id: usize,
+        mut allow: impl FnMut(&S::SchedItem, usize) -> bool,
+        mut expired: impl FnMut() -> bool,
+    ) -> Option<(S::SchedItem, Option<usize>)> {
+        let local = self.pick_run_next(hart_id);
+        #[cfg(feature = "urgent")]
+        let local = local.or_else(|| self.pick_urgent(hart_id));
+        let local = local
+            .or_else(|| self.pick_local(hart_id))
+            .or_else(|| self.pick_overflow(hart_id));
+        if let Some(task) = local {
+            return Some((task, None));
+        }
+        #[cfg(feature = "urgent")]
+        if !expired() {
+            if let Some((from, task)) = self.steal_urgent(hart_id, &mut allow) {
+                return Some((task, Some(from)));
+            }
+        }
+        match self.steal_until(hart_id, &mut allow, &mut expired) {
+            Ok((from, task)) => Some((task, Some(from))),
+            Err(probed) => {
+                (self.on_steal_fail)(hart_id, probed);
+                if expired() {
+                    return None;
+                }
+                self.supply(hart_id).map(|task| (task, None))
+            }
+        }
+    }
+
+    /// Asks the [fallback source](Self::set_fallback) for a task for
+    /// `hart_id`.
+    fn supply(&self, hart_id: usize) -> Option<S::SchedItem> {
+        let task = self.fallback.as_ref()?.supply(hart_id)?;
+        self.harts[hart_id].stats.record_pick();
+        Some(task)
+    }
+
+    /// Sets the [`FallbackSource`](crate::FallbackSource) asked for work when
+    /// a hart finds nothing locally and nothing to steal. Without one
+    /// (the default) such a pick returns `None`.
+    pub fn set_fallback(&mut self, fallback: Arc<dyn FallbackSource<S::SchedItem> + Send + Sync>) {
+        self.fallback = Some(fallback);
+    }
+
+    /// Sets the [`TaskSink`](crate::TaskSink) given the tasks the scheduler
+    /// lets go of: those an [overflow policy](OverflowPolicy) or
+    /// [`reinit`](Self::reinit) discards, and
+    /// those emptied out by [`clear`](Self::clear) or still queued when the
+    /// scheduler is dropped, after any [leak check](Self::set_on_leak).
+    /// Without one (the default) they are dropped.
+    ///
+    /// At drop the queues are emptied through exclusive access rather than
+    /// their locks, and like the leak check this is skipped while unwinding
+    /// with the `std` feature.
+    pub fn set_task_sink(&mut self, sink: Arc<dyn TaskSink<S::SchedItem> + Send + Sync>) {
+        self.sink = Some(sink);
+    }
+
+    /// Hands `task` to the [sink](Self::set_task_sink), or drops it.
+    fn discard(&self, task: S::SchedItem) {
+        match &self.sink {
+            Some(sink) => sink.recycle(task),
+            None => drop(task),
+        }
+    }
+
+    /// Sets the global overflow queue, which takes the tasks
+    /// [`put_prev_task`](Self::put_prev_task) would put back on a hart whose
+    /// queue is at [capacity](Self::set_capacity). Harts pick from it once
+    /// their own queue is empty, before stealing. Without one (the default)
+    /// such tasks spill to another hart with room, or go back on the full
+    /// queue if there is none.
+    pub fn set_overflow_queue(&mut self, mut queue: S) {
+        // init() would not run again for it
+        if *self.initialized.get_mut() {
+            queue.init();
+        }
+        self.overflow_queue = Some(SideQueue {
+            queue: lock_api::Mutex::new(queue),
+            len: AtomicUsize::new(0),
+        });
+    }
+
+    /// Returns the number of tasks in the
+    /// [overflow queue](Self::set_overflow_queue).
+    pub fn overflow_len(&self) -> usize {
+        self.overflow_queue
+            .as_ref()
+            .map_or(0, |overflow| overflow.len.load(Ordering::Relaxed))
+    }
+
+    /// Like [`pick_next_task`](Self::pick_next_task), but keeps the
+    /// [`TaskMeta`](crate::TaskMeta) of the tasks up to date and honours the
+    /// per-task policies configured on the scheduler, such as the
+    /// [steal limit](Self::set_steal_limit).
+    pub fn pick_next_task_tracked(&self) -> Option<S::SchedItem>
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        let hart_id = H::hart_id();
+        let now = self.steal_back.map_or(0, |(_, now)| now());
+        self.pick_with(
+            hart_id,
+            self.tracked_allow(hart_id, now),
+            || false,
+            |task, from| match from {
+                Some(from) => task.task_meta().record_steal(from, hart_id, now),
+                None => task.task_meta().record_local_pick(hart_id),
+            },
+        )
+    }
+
+    /// Tells whether `hart_id` may take a task on the tracked paths at time
+    /// `now`, from its own queue or another's.
+    fn tracked_allow(
+        &self,
+        hart_id: usize,
+        now: usize,
+    ) -> impl Fn(&S::SchedItem, usize) -> bool + '_
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        let limit = self.steal_limit.unwrap_or(usize::MAX);
+        move |task, _| {
+            let meta = task.task_meta();
+            meta.class()
+                .is_none_or(|class| self.class_allows(class, hart_id))
+                && meta
+                    .affinity()
+                    .is_none_or(|mask| mask_allows(mask, hart_id))
+                && meta.is_migratable()
+                && meta.steal_count() < limit
+                && !self
+                    .steal_back
+                    .is_some_and(|(window, _)| meta.stolen_from_within(hart_id, now, window))
+        }
+    }
+
+    /// Makes every thief probe the hart it last stole from before the others,
+    /// for producer/consumer pipelines where one hart keeps queueing the
+    /// work another ends up stealing. Off by default, thieves then move on
+    /// round robin to the victim after the one they robbed.
+    pub fn set_producer_affinity(&mut self, enabled: bool) {
+        self.producer_affinity = enabled;
+    }
+
+    /// Seeds every steal scan from a global steal epoch offset by the hart
+    /// id, instead of the victim after the one the thief last robbed. The
+    /// epoch advances with every successful steal.
+    ///
+    /// Per-hart cursors start out equal and tend to stay in step, so harts
+    /// going idle together probe the same victim at the same instant and
+    /// contend on its lock. With the epoch, thieves scanning at the same
+    /// time start `hart_id` apart and reach any given victim at different
+    /// points of their scans, which de-phases them without an RNG. Off by
+    /// default.
+    pub fn set_global_steal_epoch(&mut self, enabled: bool) {
+        self.steal_epoch = enabled.then(|| AtomicUsize::new(0));
+    }
+
+    /// Returns the victim the next steal scan of `hart_id` starts at, see
+    /// [`set_global_steal_epoch`](Self::set_global_steal_epoch).
+    pub fn hart_cursor(&self, hart_id: usize) -> usize {
+        match &self.steal_epoch {
+            Some(epoch) => (epoch.load(Ordering::Relaxed) + hart_id) % SMP,
+            None => self.harts[hart_id].steal_cursor.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets how many times a thief retries a contended victim lock before
+    /// moving on, spinning in between. Stealing then gets through brief
+    /// contention windows while never blocking for long, a middle ground
+    /// between `try_lock` and `lock`. 0 (the default) skips a contended
+    /// victim right away.
+    pub fn set_steal_spin_budget(&mut self, spins: usize) {
+        self.steal_spins = spins;
+    }
+
+    /// Sets how many times in a row a task may be stolen by
+    /// [`pick_next_task_tracked`](Self::pick_next_task_tracked) before it is
+    /// left to run on the hart it sits on. `None` (the default) disables the
+    /// limit.
+    pub fn set_steal_limit(&mut self, limit: Option<usize>) {
+        self.steal_limit = limit;
+    }
+
+    /// Partitions the harts between task classes: class `i` may only run on
+    /// the harts whose bit is set in `masks[i]`.
+    ///
+    /// Harts which belong to no class only ever run tasks added without a
+    /// class, and steal those only. Since classes are recorded in the
+    /// [`TaskMeta`](crate::TaskMeta), only
+    /// [`pick_next_task_tracked`](Self::pick_next_task_tracked) keeps stealing
+    /// within a partition.
+    pub fn set_task_classes(&mut self, masks: Vec<u64>) {
+        let all = if SMP >= 64 { u64::MAX } else { (1 << SMP) - 1 };
+        for mask in masks.iter() {
+            assert!(
+                *mask != 0 && mask & !all == 0,
+                "class mask {mask:#x} must name some of the {SMP} harts"
+            );
+        }
+        self.class_masks = masks;
+    }
+
+    /// Adds a task of the given `class`, see
+    /// [`set_task_classes`](Self::set_task_classes).
+    ///
+    /// The task goes to the current hart if the class may run there, and to
+    /// the least loaded hart of the class otherwise.
+    pub fn add_task_classed(&self, class: usize, task: S::SchedItem)
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        task.task_meta().set_class(class);
+        let target = self.allowed_target(H::hart_id(), |i| self.class_allows(class, i));
+        self.add_task_on(target, task);
+    }
+
+    /// Returns `hart_id` if `allows` it and it is online, and the least
+    /// loaded hart `allows` accepts otherwise, which must exist.
+    fn allowed_target(&self, hart_id: usize, allows: impl Fn(usize) -> bool) -> usize {
+        if allows(hart_id) && self.is_hart_online(hart_id) {
+            return hart_id;
+        }
+        // prefer the online harts, if any is left
+        (0..SMP)
+            .filter(|&i| allows(i))
+            .min_by_key(|&i| (!self.is_hart_online(i), self.queue_len(i)))
+            .unwrap()
+    }
+
+    fn class_allows(&self, class: usize, hart_id: usize) -> bool {
+        mask_allows(self.class_masks[class], hart_id)
+    }
+
+    /// Adds a task which may only run on the harts whose bit is set in
+    /// `mask`, recording the mask in its [`TaskMeta`](crate::TaskMeta).
+    ///
+    /// The task goes to the current hart if the mask allows it, and to the
+    /// least loaded allowed hart otherwise. As with classes, only
+    /// [`pick_next_task_tracked`](Self::pick_next_task_tracked) keeps the
+    /// task on its harts when stealing.
+    pub fn add_task_with_affinity(&self, task: S::SchedItem, mask: u64)
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        let all = if SMP >= 64 { u64::MAX } else { (1 << SMP) - 1 };
+        assert!(
+            mask != 0 && mask & !all == 0,
+            "affinity mask {mask:#x} must name some of the {SMP} harts"
+        );
+        task.task_meta().set_affinity(mask);
+        let target = self.allowed_target(H::hart_id(), |i| mask_allows(mask, i));
+        self.add_task_on(target, task);
+    }
+
+    /// Adds a task to the current hart and marks it as not
+    /// [migratable](crate::TaskMeta::set_migratable), so that it never
+    /// leaves the hart on its own.
+    ///
+    /// From the first pinned task on, every thief checks the flag, whichever
+    /// pick it steals for, and so do
+    /// [`migrate_half_to_idle`](Self::migrate_half_to_idle) and the overflow
+    /// of a full queue. Only explicit moves, such as
+    /// [`migrate_task`](Self::migrate_task) or
+    /// [`flush_to_hart`](Self::flush_to_hart) and thus
+    /// [`scale_harts`](Self::scale_harts), still move pinned tasks.
+    pub fn add_task_pinned(&self, task: S::SchedItem)
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        if !self.pinning.load(Ordering::Acquire) {
+            *self.pin_check.lock() = Some(Self::migratable);
+            self.pinning.store(true, Ordering::Release);
+        }
+        task.task_meta().set_migratable(false);
+        self.add_task(task);
+    }
+
+    fn migratable(task: &S::SchedItem) -> bool
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        task.task_meta().is_migratable()
+    }
+
+    /// Tells whether `task` may leave its hart, see
+    /// [`add_task_pinned`](Self::add_task_pinned).
+    fn may_migrate(&self, task: &S::SchedItem) -> bool {
+        if !self.pinning.load(Ordering::Acquire) {
+            return true;
+        }
+        let check = *self.pin_check.lock();
+        check.is_none_or(|check| check(task))
+    }
+
+    /// Tells whether `to` may steal `task` from `from`, as far as the
+    /// [filter](Self::set_steal_filter) and pinning go.
+    fn may_steal(&self, task: &S::SchedItem, from: usize, to: usize) -> bool {
+        self.may_migrate(task)
+            && self
+                .steal_filter
+                .as_ref()
+                .is_none_or(|f| f.can_steal(task, from, to))
+    }
+
+    /// Returns the affinity mask `task` was added with, or `None` if it may
+    /// run anywhere.
+    ///
+    /// The mask travels with the task in its [`TaskMeta`](crate::TaskMeta),
+    /// so this needs no lookup and works whether the task is queued, running
+    /// or blocked.
+    pub fn affinity_of(&self, task: &S::SchedItem) -> Option<u64>
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        task.task_meta().affinity()
+    }
+
+    /// Changes the affinity mask of `task` to `new_mask`, and if the task is
+    /// queued on a hart the new mask excludes, moves it to the least loaded
+    /// online hart the mask allows right away. Returns whether the task was
+    /// moved.
+    ///
+    /// Hands `new_mask` back as `Err`, leaving the old mask in place, if it
+    /// names no online hart. A task which is not queued, e.g. because it is
+    /// running, only gets its mask updated, which steals honour from then on.
+    /// The task is located as by [`contains_task`](Self::contains_task), and
+    /// only taken off the hart found to hold it.
+    pub fn update_affinity(&self, task: &S::SchedItem, new_mask: u64) -> Result<bool, u64>
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        if !(0..SMP).any(|i| mask_allows(new_mask, i) && self.is_hart_online(i)) {
+            return Err(new_mask);
+        }
+        task.task_meta().set_affinity(new_mask);
+        let found = self
+            .contains_task(task)
+            .filter(|&i| !mask_allows(new_mask, i));
+        let Some(task) = found.and_then(|i| self.remove_task_on(i, task)) else {
+            return Ok(false);
+        };
+        let target = self.allowed_target(H::hart_id(), |i| mask_allows(new_mask, i));
+        self.add_task_on(target, task);
+        Ok(true)
+    }
+
+    /// Forbids [`pick_next_task_tracked`](Self::pick_next_task_tracked) from
+    /// stealing a task back onto the hart it was just stolen from, for
+    /// `window` ticks of the `now` clock. This stops a task from ping-ponging
+    /// between two idle-prone harts. A `window` of 0 (the default) disables
+    /// the check.
+    pub fn set_steal_back_window(&mut self, window: usize, now: fn() -> usize) {
+        self.steal_back = (window > 0).then_some((window, now));
+    }
+
+    /// Picks the next task, parking the current hart with the
+    /// [`Parker`](crate::Parker) until one is available.
+    ///
+    /// Panics if no parker was [set](Self::set_parker).
+    pub fn wait_for_task(&self) -> S::SchedItem {
+        let hart_id = H::hart_id();
+        let parker = self
+            .parker
+            .as_ref()
+            .expect("wait_for_task() needs a Parker, see set_parker()");
+        loop {
+            if let Some(task) = self.pick_next_task_on(hart_id) {
+                return task;
+            }
+            let parked = &self.harts[hart_id].parked;
+            parked.store(true, Ordering::SeqCst);
+            // pairs with the fence in notify(): either the enqueue sees us
+            // parked, or we see its task here
+            fence(Ordering::SeqCst);
+            if let Some(task) = self.pick_next_task_on(hart_id) {
+                parked.store(false, Ordering::SeqCst);
+                return task;
+            }
+            parker.park(hart_id);
+            parked.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Sets the [`Parker`](crate::Parker) used by
+    /// [`wait_for_task`](Self::wait_for_task). Once set, every enqueue wakes
+    /// the target hart if it is parked, or else some other parked hart which
+    /// may steal the task.
+    pub fn set_parker(&mut self, parker: Arc<dyn Parker + Send + Sync>) {
+        self.parker = Some(parker);
+    }
+
+    /// Sets the [`StealFilter`](crate::StealFilter) consulted before any task
+    /// is stolen. Without one every steal is allowed.
+    pub fn set_steal_filter(&mut self, filter: Arc<dyn StealFilter<S::SchedItem> + Send + Sync>) {
+        self.steal_filter = Some(filter);
+    }
+
+    /// Sets the [`WakeHart`](crate::WakeHart) told about every enqueue, along
+    /// with the new length of the queue, save for those of the current hart
+    /// on itself.
+    pub fn set_waker(&mut self, waker: Arc<dyn WakeHart + Send + Sync>) {
+        self.waker = Some(waker);
+    }
+
+    /// Tells the [waker](Self::set_waker) and the harts parked in
+    /// `wait_for_task` that `hart_id` got work.
+    ///
+    /// A hart enqueueing on itself is obviously awake, so it is not woken;
+    /// a parked hart may still be unparked to steal the work.
+    fn notify(&self, hart_id: usize) {
+        self.notify_as(H::hart_id(), hart_id);
+    }
+
+    /// Like `notify`, for an enqueue made by the hart `current`.
+    fn notify_as(&self, current: usize, hart_id: usize) {
+        let remote = hart_id != current;
+        if let Some(waker) = self.waker.as_ref().filter(|_| remote) {
+            waker.wake(hart_id, self.queue_len(hart_id));
+        }
+        let Some(parker) = self.parker.as_ref() else {
+            return;
+        };
+        fence(Ordering::SeqCst);
+        if remote && self.harts[hart_id].parked.load(Ordering::SeqCst) {
+            parker.unpark(hart_id);
+        } else if let Some(idle) = (0..SMP).find(|&i| self.harts[i].parked.load(Ordering::SeqCst)) {
+            parker.unpark(idle);
+        }
+    }
+
+    /// Picks up to `max` tasks of the current hart at once, appending them to
+    /// `out`, and returns how many were appended.
+    ///
+    /// Tasks come in the order [`pick_next_task`](Self::pick_next_task)
+    /// would pick them: the [run next slot](Self::set_run_next_slots), the
+    /// urgent lane, the local queue, locked once for the whole batch, then
+    /// the [overflow queue](Self::set_overflow_queue). Only if all of them
+    /// held nothing is a single task stolen, as by `pick_next_task`, and
+    /// nothing is picked while the [budget](Self::budget_remaining) is
+    /// exhausted. `out` is not cleared and only grows when it lacks room: a
+    /// buffer kept around with capacity for `max` tasks and cleared between
+    /// calls never allocates.
+    pub fn pick_next_tasks_into(&self, out: &mut Vec<S::SchedItem>, max: usize) -> usize {
+        self.pick_batch(H::hart_id(), max, |task| out.push(task))
+    }
+
+    /// Like [`pick_next_tasks_into`](Self::pick_next_tasks_into), with a
+    /// fixed buffer: fills `out` from the start, at most `out.len()` tasks,
+    /// and returns how many slots were written. The other slots are left as
+    /// they were.
+    pub fn pick_next_tasks_slice(&self, out: &mut [Option<S::SchedItem>]) -> usize {
+        let mut slots = out.iter_mut();
+        self.pick_batch(H::hart_id(), slots.len(), |task| {
+            *slots.next().unwrap() = Some(task);
+        })
+    }
+
+    /// Hands up to `max` tasks of `hart_id` to `sink`, falling back to one
+    /// stolen task when the hart has nothing of its own.
+    ///
+    /// Tasks are taken in the order of [`pick_next_task`](Self::pick_next_task):
+    /// the run next slot, the urgent lane, the local queue, then the
+    /// overflow queue.
+    fn pick_batch(&self, hart_id: usize, max: usize, mut sink: impl FnMut(S::SchedItem)) -> usize {
+        if max == 0 || self.budget_remaining(hart_id) == 0 {
+            return 0;
+        }
+        self.check_init();
+        let mut picked = 0;
+        if let Some(task) = self.pick_run_next(hart_id) {
+            sink(task);
+            picked += 1;
+        }
+        #[cfg(feature = "urgent")]
+        while picked < max {
+            let Some(task) = self.pick_urgent(hart_id) else {
+                break;
+            };
+            sink(task);
+            picked += 1;
+        }
+        let mut queue = self.local_queues[hart_id].lock();
+        let mut local = 0;
+        while picked + local < max {
+            let Some(task) = queue.pick_next_task() else {
+                break;
+            };
+            self.log(hart_id, ReplayOp::Pick, &task);
+            sink(task);
+            local += 1;
+        }
+        if local == 0 {
+            self.clear_len(hart_id);
+            drop(queue);
+        } else {
+            let emptied = self.shrink_len(hart_id, &queue, local);
+            drop(queue);
+            let stats = &self.harts[hart_id].stats;
+            for _ in 0..local {
+                stats.record_dequeue();
+                stats.record_pick();
+            }
+            self.shrunk(hart_id, emptied);
+            picked += local;
+        }
+        while picked < max {
+            let Some(task) = self.pick_overflow(hart_id) else {
+                break;
+            };
+            sink(task);
+            picked += 1;
+        }
+        if picked == 0 {
+            // nothing of our own, steal a single task
+            return match self.pick_next_task_on(hart_id) {
+                Some(task) => {
+                    sink(task);
+                    1
+                }
+                None => 0,
+            };
+        }
+        self.end_idle_streak(hart_id);
+        picked
+    }
+
+    /// Picks the next task from the queue of `hart_id` itself.
+    fn pick_local(&self, hart_id: usize) -> Option<S::SchedItem> {
+        self.check_init();
+        let mut queue = self.local_queues[hart_id].lock();
+        let hart = &self.harts[hart_id];
+        let Some(task) = queue.pick_next_task() else {
+            // the queue is empty whatever the counter says, e.g. when the
+            // inner scheduler discards tasks on its own
+            self.clear_len(hart_id);
+            return None;
+        };
+        let emptied = self.shrink_len(hart_id, &queue, 1);
+        drop(queue);
+        self.log(hart_id, ReplayOp::Pick, &task);
+        hart.stats.record_dequeue();
+        hart.stats.record_pick();
+        self.shrunk(hart_id, emptied);
+        Some(task)
+    }
+
+    /// Picks a task for `hart_id` from the
+    /// [overflow queue](Self::set_overflow_queue), if any.
+    fn pick_overflow(&self, hart_id: usize) -> Option<S::SchedItem> {
+        let overflow = self.overflow_queue.as_ref()?;
+        if overflow.len.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let mut queue = overflow.queue.lock();
+        let Some(task) = queue.pick_next_task() else {
+            overflow.len.store(0, Ordering::Relaxed);
+            return None;
+        };
+        saturating_sub(&overflow.len, 1);
+        drop(queue);
+        self.log(hart_id, ReplayOp::Pick, &task);
+        self.harts[hart_id].stats.record_pick();
+        Some(task)
+    }
+
+    /// Runs what follows tasks leaving the queue of `hart_id`, once its lock
+    /// is dropped: the [`on_empty`](Self::set_on_empty) callback if they
+    /// `emptied` it, and the wakeup of producers blocked on it.
+    fn shrunk(&self, hart_id: usize, emptied: bool) {
+        if emptied {
+            (self.on_empty)(hart_id);
+        }
+        if self.overflow != OverflowPolicy::Block {
+            return;
+        }
+        // pairs with the fence in add_blocking(): either we see the producer
+        // waiting, or it sees the room we made
+        fence(Ordering::SeqCst);
+        for (i, hart) in self.harts.iter().enumerate() {
+            if hart.blocked_on.load(Ordering::SeqCst) == hart_id {
+                if let Some(parker) = self.parker.as_ref() {
+                    parker.unpark(i);
+                }
+            }
+        }
+    }
+
+    /// Accounts for `n` tasks leaving the queue of `hart_id`, which must be
+    /// locked. Returns true if this emptied the queue; the caller then runs
+    /// the [`on_empty`](Self::set_on_empty) callback once it dropped the lock.
+    fn shrink_len(&self, hart_id: usize, queue: &S, n: usize) -> bool {
+        let hart = &self.harts[hart_id];
+        if let Some(count) = self.count {
+            let len = count(queue);
+            let prev = hart.len.swap(len, Ordering::Relaxed);
+            hart.stats.record_len(len);
+            return prev > 0 && len == 0;
+        }
+        let prev = saturating_sub(&hart.len, n);
+        hart.stats.record_len(prev.saturating_sub(n));
+        n > 0 && (1..=n).contains(&prev)
+    }
+
+    /// Resets the length counter of `hart_id` to what its locked `queue`
+    /// holds after the queue was drained, if a
+    /// [count](Self::set_count) was set.
+    fn sync_len(&self, hart_id: usize, queue: &S) {
+        if let Some(count) = self.count {
+            let len = count(queue);
+            self.harts[hart_id].len.store(len, Ordering::Relaxed);
+            self.harts[hart_id].stats.record_len(len);
+        }
+    }
+
+    /// Adds `n` to the length counter of `hart_id`.
+    fn grow_len(&self, hart_id: usize, n: usize) {
+        let hart = &self.harts[hart_id];
+        hart.stats
+            .record_len(hart.len.fetch_add(n, Ordering::Relaxed) + n);
+    }
+
+    /// Resets the length counter of `hart_id`, whose queue was found empty.
+    fn clear_len(&self, hart_id: usize) {
+        self.harts[hart_id].len.store(0, Ordering::Relaxed);
+        self.harts[hart_id].stats.record_len(0);
+    }
+
+    /// Sets the callback run with the id of a hart whose queue just became
+    /// empty because its last task was picked (locally or by a thief),
+    /// removed or flushed away.
+    ///
+    /// It is called exactly once per transition, after the queue lock has
+    /// been released. The default does nothing.
+    pub fn set_on_empty(&mut self, on_empty: fn(usize)) {
+        self.on_empty = on_empty;
+    }
+
+    /// Sets the callback run when the scheduler is dropped with tasks still
+    /// queued, which usually means they were leaked at shutdown. It gets the
+    /// number of tasks left on every hart, and can log them or panic.
+    ///
+    /// The lengths are read from the counters without locking. With the
+    /// `std` feature the callback is skipped while the thread unwinds from a
+    /// panic; without it there is no telling, so the callback runs during
+    /// unwinding too and must not panic then. By default nothing is checked.
+    pub fn set_on_leak(&mut self, on_leak: fn(&[usize])) {
+        self.on_leak = Some(on_leak);
+    }
+
+    /// Sets the callback run when a pick found nothing locally and then
+    /// nothing to steal, with the hart and how many victims it probed (tried
+    /// to lock). This is the moment a hart is about to go idle, e.g. to
+    /// start an idle timer; it runs before the
+    /// [fallback source](Self::set_fallback) is asked. The default does
+    /// nothing.
+    pub fn set_on_steal_fail(&mut self, on_steal_fail: fn(usize, usize)) {
+        self.on_steal_fail = on_steal_fail;
+    }
+
+    /// Sets the cost model deciding whether a steal is worth migrating a
+    /// task, e.g. when cold caches cost more than waiting.
+    ///
+    /// It is called with the thief, the victim and the victim's queue length
+    /// before the victim is locked, and victims it returns false for are
+    /// skipped; if it refuses them all the thief finds nothing to steal. The
+    /// default steals from any victim with work.
+    pub fn set_should_steal(&mut self, should_steal: fn(usize, usize, usize) -> bool) {
+        self.should_steal = should_steal;
+    }
+
+    /// Sets a function called with every stolen task and the harts it moves
+    /// from and to. When it returns a priority, that priority is applied
+    /// through the thief's inner `set_priority`, e.g. to keep a migrated
+    /// task from being starved by the thief's own higher priority tasks.
+    pub fn set_steal_boost(&mut self, boost: StealBoost<S::SchedItem>) {
+        self.steal_boost = Some(boost);
+    }
+
+    /// Places every hart on a NUMA node, `node_of[i]` being the node of hart
+    /// `i`, and sets a function called with every task stolen across nodes
+    /// and the harts it moves from and to. Its working set is now remote, so
+    /// this is the point to trigger page migration or mark the task for
+    /// memory rebalancing. Steals within a node are not reported.
+    pub fn set_on_cross_node_steal(
+        &mut self,
+        node_of: [usize; SMP],
+        hook: CrossNodeSteal<S::SchedItem>,
+    ) {
+        self.numa = Some((node_of, hook));
+    }
+
+    /// Steals a task from the other harts, returning it along with the victim
+    /// it came from. A victim keeps the tasks not accepted by `allow` (given
+    /// the task and the victim) where they were, and the first one behind
+    /// them which is accepted is stolen instead.
+    ///
+    /// Victims whose length counter reads zero are skipped without locking.
+    /// A victim may still turn out empty (or contended) once we get to it; the
+    /// scan then moves on to the next victim, and if the whole pass came up
+    /// short that way it is repeated, at most [`STEAL_PASSES`] times.
+    ///
+    /// Each thief scans from its [cursor](Self::hart_cursor): where its last
+    /// successful steal left off, the victim after the one it robbed, so busy
+    /// victims are drained round robin rather than lowest index first. With
+    /// [producer affinity](Self::set_producer_affinity) the victim it robbed
+    /// last is probed first.
+    ///
+    /// When nothing is stolen, returns how many victims were probed, that is
+    /// how many locks were tried, over all passes.
+    fn steal(
+        &self,
+        hart_id: usize,
+        allow: impl FnMut(&S::SchedItem, usize) -> bool,
+    ) -> Result<(usize, S::SchedItem), usize> {
+        self.steal_until(hart_id, allow, || false)
+    }
+
+    /// Like [`steal`](Self::steal), giving up before the next victim probe
+    /// once `expired` returns true.
+    fn steal_until(
+        &self,
+        hart_id: usize,
+        mut allow: impl FnMut(&S::SchedItem, usize) -> bool,
+        mut expired: impl FnMut() -> bool,
+    ) -> Result<(usize, S::SchedItem), usize> {
+        let cursor = &self.harts[hart_id].steal_cursor;
+        let mut probed = 0;
+        for _ in 0..STEAL_PASSES {
+            let mut missed = false;
+            let start = self.hart_cursor(hart_id);
+            let first = self
+                .producer_affinity
+                .then(|| self.last_steal_victim(hart_id))
+                .flatten();
+            let rest = (0..SMP)
+                .map(|k| (start + k) % SMP)
+                .filter(|&i| Some(i) != first);
+            for i in first.into_iter().chain(rest) {
+                let len = self.queue_len(i);
+                if i == hart_id || len <= self.min_remain || !(self.should_steal)(hart_id, i, len) {
+                    continue;
+                }
+                if expired() {
+                    return Err(probed);
+                }
+                probed += 1;
+                match self.steal_once(hart_id, i, &mut allow) {
+                    Ok(task) => {
+                        match &self.steal_epoch {
+                            Some(epoch) => {
+                                epoch.fetch_add(1, Ordering::Relaxed);
+                            }
+                            None => cursor.store((i + 1) % SMP, Ordering::Relaxed),
+                        }
+                        return Ok((i, task));
+                    }
+                    Err(StealMiss::Refused) => {}
+                    Err(StealMiss::Empty | StealMiss::Contended) => missed = true,
+                }
+            }
+            if !missed {
+                break;
+            }
+        }
+        Err(probed)
+    }
+
+    /// Makes a single attempt at stealing a task for the current hart from
+    /// `victim`: one `try_lock`, taking the first task of its queue the
+    /// [filter](Self::set_steal_filter) allows, without retrying or moving on
+    /// to another victim. This is the building block of the steal loop, for
+    /// stealing policies built outside the scheduler.
+    ///
+    /// The [filter](Self::set_steal_filter) still applies, while victim
+    /// selection such as the [cost model](Self::set_should_steal) and the
+    /// [minimum left](Self::set_min_remain) is up to the caller.
+    pub fn try_steal_once(&self, victim: usize) -> Result<S::SchedItem, StealMiss> {
+        let hart_id = H::hart_id();
+        assert_ne!(victim, hart_id, "a hart cannot steal from itself");
+        self.check_init();
+        self.steal_once(hart_id, victim, |_, _| true)
+    }
+
+    /// Tries to lock the queue of `victim`, retrying up to the
+    /// [spin budget](Self::set_steal_spin_budget) while it is contended.
+    fn try_lock_spinning(&self, victim: usize) -> Option<lock_api::MutexGuard<'_, L, S>> {
+        let queue = &self.local_queues[victim];
+        for _ in 0..self.steal_spins {
+            if let Some(guard) = queue.try_lock() {
+                return Some(guard);
+            }
+            core::hint::spin_loop();
+        }
+        queue.try_lock()
+    }
+
+    /// Tries once to steal a task of `victim` for `hart_id`, see
+    /// [`try_steal_once`](Self::try_steal_once).
+    fn steal_once(
+        &self,
+        hart_id: usize,
+        victim: usize,
+        mut allow: impl FnMut(&S::SchedItem, usize) -> bool,
+    ) -> Result<S::SchedItem, StealMiss> {
+        let Some(mut other) = self.try_lock_spinning(victim) else {
+            self.harts[hart_id].stats.record_contention();
+            return Err(StealMiss::Contended);
+        };
+        let stolen = self.take_stolen(&mut other, |task| {
+            self.may_steal(task, victim, hart_id) && allow(task, victim)
+        });
+        let task = match stolen {
+            Ok(task) => task,
+            Err(miss) => {
+                if miss == StealMiss::Empty {
+                    // raced with the owner, the counter was stale
+                    self.clear_len(victim);
+                } else {
+                    self.sync_len(victim, &other);
+                }
+                return Err(miss);
+            }
+        };
+        let emptied = self.shrink_len(victim, &other, 1);
+        drop(other);
+        self.shrunk(victim, emptied);
+        self.stolen(victim, hart_id, &task);
+        Ok(task)
+    }
+
+    /// Steals tasks into the current hart's queue until it holds
+    /// `target_len` tasks or nothing is left to steal, and returns how many
+    /// were pulled. Calling this before a busy phase moves the cost of
+    /// migration, remote locks included, to a convenient point.
+    ///
+    /// Victims are chosen as by [`pick_next_task`](Self::pick_next_task),
+    /// so the [filter](Self::set_steal_filter), the
+    /// [cost model](Self::set_should_steal) and the
+    /// [minimum left](Self::set_min_remain) all apply, and the queue is not
+    /// filled beyond its [capacity](Self::set_capacity). An offline hart
+    /// pulls nothing.
+    pub fn prefetch_work(&self, target_len: usize) -> usize {
+        let hart_id = H::hart_id();
+        if !self.is_hart_online(hart_id) {
+            return 0;
+        }
+        let target_len = target_len.min(self.capacity.unwrap_or(usize::MAX));
+        let mut pulled = 0;
+        while self.queue_len(hart_id) < target_len {
+            let Ok((_, task)) = self.steal(hart_id, |_, _| true) else {
+                break;
+            };
+            let mut queue = self.local_queues[hart_id].lock();
+            self.enqueue_locked(hart_id, &mut queue, task);
+            pulled += 1;
+        }
+        pulled
+    }
+
+    /// Picks the globally most deserving task for the current hart: the head
+    /// with the lowest `vruntime` among all harts, stolen if it is not the
+    /// local one, with ties going to the local queue.
+    ///
+    /// Per-hart CFS with stealing only on idle lets a task on a lightly
+    /// loaded hart run more than one on a busy hart; comparing the heads
+    /// across harts on every pick evens that out. The inner `CFScheduler`
+    /// keeps its vruntimes private, so `vruntime` reads them from the task,
+    /// e.g. a weighted runtime the task type accounts for itself.
+    ///
+    /// This costs a lock of every non-empty hart per call, one at a time,
+    /// O(SMP) remote cache traffic the plain
+    /// [`pick_next_task`](Self::pick_next_task) avoids. Heads passed over go
+    /// back as by `put_prev_task`, behind the tasks of equal vruntime. The
+    /// [filter](Self::set_steal_filter) applies to remote heads.
+    ///
+    /// Only the normal queues are compared. As with `pick_next_task`, nothing
+    /// is picked while the [budget](Self::budget_remaining) is exhausted, and
+    /// the [run next slot](Self::set_run_next_slots) and the urgent lane of
+    /// the hart go first, whatever the vruntimes.
+    #[cfg(feature = "cfs")]
+    pub fn pick_next_task_weighted_fair(
+        &self,
+        vruntime: impl Fn(&S::SchedItem) -> isize,
+    ) -> Option<S::SchedItem> {
+        let hart_id = H::hart_id();
+        if self.budget_remaining(hart_id) == 0 {
+            return None;
+        }
+        self.check_init();
+        let first = self.pick_run_next(hart_id);
+        #[cfg(feature = "urgent")]
+        let first = first.or_else(|| self.pick_urgent(hart_id));
+        if let Some(task) = first {
+            self.end_idle_streak(hart_id);
+            return Some(task);
+        }
+        // the head picked so far, and whether taking it emptied its hart
+        let mut best: Option<(isize, usize, S::SchedItem, bool)> = None;
+        for i in (0..SMP).filter(|&i| self.queue_len(i) > 0) {
+            let mut queue = self.local_queues[i].lock();
+            let Some(head) = queue.pick_next_task() else {
+                self.clear_len(i);
+                continue;
+            };
+            let key = vruntime(&head);
+            let vetoed = i != hart_id && !self.may_steal(&head, i, hart_id);
+            let beaten = best
+                .as_ref()
+                .is_some_and(|&(best_key, j, ..)| (best_key, j != hart_id) <= (key, i != hart_id));
+            if vetoed || beaten {
+                queue.put_prev_task(head, false);
+                continue;
+            }
+            let emptied = self.shrink_len(i, &queue, 1);
+            drop(queue);
+            if let Some((_, j, prev, _)) = best.replace((key, i, head, emptied)) {
+                let mut queue = self.local_queues[j].lock();
+                queue.put_prev_task(prev, false);
+                self.grow_len(j, 1);
+            }
+        }
+        let Some((_, from, task, emptied)) = best else {
+            return self.pick_next_task_on(hart_id);
+        };
+        self.shrunk(from, emptied);
+        if from == hart_id {
+            self.log(hart_id, ReplayOp::Pick, &task);
+            self.harts[hart_id].stats.record_dequeue();
+            self.harts[hart_id].stats.record_pick();
+        } else {
+            self.stolen(from, hart_id, &task);
+        }
+        self.end_idle_streak(hart_id);
+        Some(task)
+    }
+
+    /// Steals a task for the current hart from the hart with the longest
+    /// queue, according to the length counters.
+    ///
+    /// Each victim is tried as by [`try_steal_once`](Self::try_steal_once),
+    /// with the [spin budget](Self::set_steal_spin_budget) for its lock. If
+    /// that lock is busy, the hart turns out empty once locked, or the
+    /// [`StealFilter`](crate::StealFilter) refuses all its tasks, the next
+    /// longest is tried, until no other hart has queued tasks.
+    pub fn steal_from_longest(&self) -> Option<S::SchedItem> {
+        let (task, _) = self.steal_from_longest_on(H::hart_id(), |_, _| true)?;
+        Some(task)
+    }
+
+    /// Like [`steal_from_longest`](Self::steal_from_longest), but keeps the
+    /// [`TaskMeta`](crate::TaskMeta) of the stolen task up to date and
+    /// honours the per-task policies, as
+    /// [`pick_next_task_tracked`](Self::pick_next_task_tracked) does.
+    pub fn steal_from_longest_tracked(&self) -> Option<S::SchedItem>
+    where
+        S::SchedItem: HasTaskMeta,
+    {
+        let hart_id = H::hart_id();
+        let now = self.steal_back.map_or(0, |(_, now)| now());
+        let (task, from) = self.steal_from_longest_on(hart_id, self.tracked_allow(hart_id, now))?;
+        task.task_meta().record_steal(from, hart_id, now);
+        Some(task)
+    }
+
+    /// Steals a task of the longest queue `allow` lets `hart_id` take, along
+    /// with the hart it was taken from.
+    fn steal_from_longest_on(
+        &self,
+        hart_id: usize,
+        mut allow: impl FnMut(&S::SchedItem, usize) -> bool,
+    ) -> Option<(S::SchedItem, usize)> {
+        let mut tried = [false; SMP];
+        tried[hart_id] = true;
+        loop {
+            // ties go to the lowest index
+            let victim = (0..SMP)
+                .rev()
+                .filter(|&i| {
+                    let len = self.queue_len(i);
+                    !tried[i] && len > self.min_remain && (self.should_steal)(hart_id, i, len)
+                })
+                .max_by_key(|&i| self.queue_len(i))?;
+            tried[victim] = true;
+            if let Ok(task) = self.steal_once(hart_id, victim, &mut allow) {
+                return Some((task, victim));
+            }
+        }
+    }
+
+    /// Sets how many tasks thieves leave on a victim: a hart with at most
+    /// `min_remain` queued tasks is not stolen from, so it stays busy rather
+    /// than going idle and stealing back. The default of 0 steals whenever
+    /// there is anything to steal.
+    ///
+    /// The check reads the length counters without locking, so it is a
+    /// heuristic under concurrency.
+    pub fn set_min_remain(&mut self, min_remain: usize) {
+        self.min_remain = min_remain;
+    }
+
+    /// Sets which end of a victim's queue thieves take from.
+    ///
+    /// [`StealEnd::Head`], the default, takes the task the victim would run
+    /// next, so every queue keeps being drained in its own order and the
+    /// no-starvation argument of [`pick_next_task`](Self::pick_next_task)
+    /// holds.
+    ///
+    /// [`StealEnd::Tail`] leaves the victim its next tasks and moves the one
+    /// it would run last, typically the newest. The inner schedulers only
+    /// give access to their head, so this drains and refills the whole victim
+    /// queue under its lock, which is O(length) per steal. The head of the
+    /// victim can then be passed over by newer tasks, but only while those
+    /// are stolen.
+    pub fn set_steal_end(&mut self, end: StealEnd) {
+        self.steal_end = end;
+    }
+
+    /// Takes the task a thief gets from the locked victim `queue`: the first
+    /// one from the stolen end which `allow` accepts.
+    ///
+    /// Tasks the thief may not have stay exactly where they were. A head
+    /// which is allowed is taken without touching the rest of the queue;
+    /// otherwise the queue is drained to look behind it and refilled in its
+    /// order.
+    fn take_stolen(
+        &self,
+        queue: &mut S,
+        mut allow: impl FnMut(&S::SchedItem) -> bool,
+    ) -> Result<S::SchedItem, StealMiss> {
+        let (found, tasks) = match self.steal_end {
+            StealEnd::Head => {
+                let head = queue.pick_next_task().ok_or(StealMiss::Empty)?;
+                if allow(&head) {
+                    return Ok(head);
+                }
+                let mut tasks = drain_queue(queue);
+                let found = tasks.iter().position(&mut allow).map(|i| tasks.remove(i));
+                tasks.insert(0, head);
+                (found, tasks)
+            }
+            StealEnd::Tail => {
+                let mut tasks = drain_queue(queue);
+                if tasks.is_empty() {
+                    return Err(StealMiss::Empty);
+                }
+                let found = tasks.iter().rposition(&mut allow).map(|i| tasks.remove(i));
+                (found, tasks)
+            }
+        };
+        refill_queue(queue, tasks);
+        found.ok_or(StealMiss::Refused)
+    }
+
+    /// Does the accounting for `task`, just stolen from `from` by `to`.
+    fn stolen(&self, from: usize, to: usize, task: &S::SchedItem) {
+        self.log(to, ReplayOp::Steal { from }, task);
+        self.harts[from].stats.record_dequeue();
+        self.harts[to].stats.record_pick();
+        self.harts[to].stats.record_steal();
+        self.harts[to].last_victim.store(from, Ordering::Relaxed);
+        if let Some(prio) = self.steal_boost.and_then(|f| f(task, from, to)) {
+            self.local_queues[to].lock().set_priority(task, prio);
+        }
+        if let Some((node_of, hook)) = &self.numa {
+            if node_of[from] != node_of[to] {
+                hook(task, from, to);
+            }
+        }
+    }
+
+    /// Puts the current task back on the current hart after it was preempted
+    /// or yielded.
+    ///
+    /// When the queues have a [capacity](Self::set_capacity) and the local
+    /// one is full, the task goes to the
+    /// [overflow queue](Self::set_overflow_queue) if there is one, and
+    /// otherwise to the least loaded other hart with room left, chosen like
+    /// [`balanced_add`](Self::balanced_add) does. Only if every hart is full
+    /// does it go back on the local queue anyway, over capacity: a running
+    /// task is never dropped.
+    ///
+    /// On an offline hart the task is added to an online one instead, as by
+    /// [`add_task_on`](Self::add_task_on).
+    ///
+    /// Where the task lands in the local queue is up to the inner scheduler,
+    /// given `preempt`: RR puts a preempted task with time slice left at the
+    /// front and anything else at the back, CFS ranks it by vruntime and FIFO
+    /// always appends it. See [`put_prev_task_ex`](Self::put_prev_task_ex)
+    /// for explicit placement.
+    ///
+    /// A task sent to the overflow queue is placed there the same way, so
+    /// `preempt` only orders it among the other overflowed tasks: the hart
+    /// serves its whole local queue first, so a preempted RR task no longer
+    /// runs next.
+    pub fn put_prev_task(&self, prev: S::SchedItem, preempt: bool) {
+        self.put_prev_on(H::hart_id(), prev, preempt);
+    }
+
+    /// Takes the current task out of scheduling because it blocks, e.g. on
+    /// I/O, and hands it back instead of requeueing it.
+    ///
+    /// A task is runnable while queued or running, and a running task which
+    /// stays runnable goes back with [`put_prev_task`](Self::put_prev_task).
+    /// One which blocks goes through this instead and is blocked until the
+    /// caller re-adds it with [`add_task`](Self::add_task) on wakeup, so the
+    /// handle returned must be kept, e.g. in the wait queue of what it
+    /// blocks on.
+    #[must_use = "a blocked task is lost unless re-added on wakeup"]
+    pub fn block_current(&self, task: S::SchedItem) -> S::SchedItem {
+        self.log(H::hart_id(), ReplayOp::Block, &task);
+        task
+    }
+
+    /// Puts `prev` back as the task `hart_id` was running, counting it in the
+    /// preemptions of the hart if `preempt` is set.
+    pub(crate) fn put_prev_on(&self, hart_id: usize, prev: S::SchedItem, preempt: bool) {
+        if preempt {
+            self.harts[hart_id].stats.record_preempt();
+        }
+        self.put_back(hart_id, prev, |queue, prev| {
+            queue.put_prev_task(prev, preempt)
+        });
+    }
+
+    /// Like [`put_prev_task`](Self::put_prev_task), with explicit control of
+    /// where the task lands in the local queue.
+    ///
+    /// The inner schedulers cannot insert at the front, so
+    /// [`Placement::Front`] drains and refills the queue under its lock,
+    /// which is O(length) and, since re-adding may reset scheduler state such
+    /// as a CFS vruntime, only suits FIFO-like queues.
+    pub fn put_prev_task_ex(&self, prev: S::SchedItem, placement: Placement) {
+        self.put_back(H::hart_id(), prev, |queue, prev| match placement {
+            Placement::Front => {
+                let tasks = drain_queue(queue);
+                queue.add_task(prev);
+                refill_queue(queue, tasks);
+            }
+            Placement::Back => queue.add_task(prev),
+            Placement::ByPriority => queue.put_prev_task(prev, false),
+        });
+    }
+
+    /// Puts `prev` back on `hart_id`, the current hart, with `place`, or
+    /// elsewhere if the hart is offline or full, see
+    /// [`put_prev_task`](Self::put_prev_task).
+    pub(crate) fn put_back(
+        &self,
+        hart_id: usize,
+        prev: S::SchedItem,
+        place: impl FnOnce(&mut S, S::SchedItem),
+    ) {
+        if !self.is_hart_online(hart_id) {
+            self.add_task_as(hart_id, hart_id, prev);
+            return;
+        }
+        let prev = match self.capacity {
+            Some(capacity) if self.queue_len(hart_id) >= capacity && self.may_migrate(&prev) => {
+                if let Some(overflow) = &self.overflow_queue {
+                    place(&mut overflow.queue.lock(), prev);
+                    overflow.len.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                match self.spill(hart_id, capacity, prev) {
+                    Ok(()) => return,
+                    Err(prev) => prev,
+                }
+            }
+            _ => prev,
+        };
+        let mut queue = self.local_queues[hart_id].lock();
+        place(&mut queue, prev);
+        self.grow_len(hart_id, 1);
+        self.sync_len(hart_id, &queue);
+    }
+
+    pub fn task_tick(&self, current: &S::SchedItem) -> bool {
+        self.task_tick_on(H::hart_id(), current)
+    }
+
+    /// Ticks `current` as the task running on `hart_id`, returning whether
+    /// that hart should reschedule.
+    pub fn task_tick_on(&self, hart_id: usize, current: &S::SchedItem) -> bool {
+        let resched = self.local_queues[hart_id].lock().task_tick(current);
+        self.update_load(hart_id);
+        resched
+    }
+
+    /// Ticks `current` on the current hart and tells whether it should be
+    /// preempted, the single decision point for a timer interrupt handler:
+    /// either the inner scheduler asks for a reschedule, or the task the
+    /// local queue would run next `outranks` it, given that task and
+    /// `current`.
+    ///
+    /// The local queue is locked once for both checks. The inner schedulers
+    /// cannot peek, so the head is found by draining and refilling the queue
+    /// as by [`remove_task_by_id`](Self::remove_task_by_id), only when the
+    /// tick did not already ask for a reschedule. The queue is refilled in
+    /// its order, so CFS tasks keep their vruntimes across ticks.
+    pub fn should_preempt_current(
+        &self,
+        current: &S::SchedItem,
+        outranks: impl Fn(&S::SchedItem, &S::SchedItem) -> bool,
+    ) -> bool {
+        let hart_id = H::hart_id();
+        let mut queue = self.local_queues[hart_id].lock();
+        let mut preempt = queue.task_tick(current);
+        if !preempt && self.queue_len(hart_id) > 0 {
+            let tasks = drain_queue(&mut *queue);
+            preempt = tasks.first().is_some_and(|next| outranks(next, current));
+            refill_queue(&mut *queue, tasks);
+            self.sync_len(hart_id, &queue);
+        }
+        drop(queue);
+        self.update_load(hart_id);
+        preempt
+    }
+
+    /// Ticks the task running on every hart, for designs where a single
+    /// timer hart accounts for everyone, and returns the per-hart reschedule
+    /// flags. Harts without a current task are skipped and report `false`.
+    pub fn task_tick_all(&self, current: &[Option<S::SchedItem>; SMP]) -> [bool; SMP] {
+        let mut resched = [false; SMP];
+        for (hart_id, task) in current.iter().enumerate() {
+            if let Some(task) = task {
+                resched[hart_id] = self.task_tick_on(hart_id, task);
+            }
+        }
+        resched
+    }
+
+    /// Moves the average queue length of `hart_id` towards its current length.
+    fn update_load(&self, hart_id: usize) {
+        let hart = &self.harts[hart_id];
+        let len = hart.len.load(Ordering::Relaxed) << LOAD_SHIFT;
+        // `task_tick_all` may tick a hart while it ticks itself, neither
+        // update may be lost
+        hart.load_avg
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+                Some(avg - (avg >> LOAD_DECAY) + (len >> LOAD_DECAY))
+            })
+            .unwrap();
+    }
+
+    /// Returns the load of `hart_id` between 0.0 and 1.0.
+    ///
+    /// With a [capacity](Self::set_capacity) this is the fill level of the
+    /// queue. Unbounded queues report `avg / (1 + avg)` of the queue length
+    /// averaged over recent [`task_tick`](Self::task_tick)s, ticks from
+    /// [`task_tick_all`](Self::task_tick_all) included, so an average of
+    /// one queued task reads 0.5; see [`hart_load_fixed`](Self::hart_load_fixed)
+    /// for the raw average.
+    pub fn hart_load(&self, hart_id: usize) -> f32 {
+        match self.capacity {
+            Some(0) => 1.0,
+            Some(capacity) => (self.queue_len(hart_id) as f32 / capacity as f32).min(1.0),
+            None => {
+                let avg = self.hart_load_fixed(hart_id) as f32 / (1 << LOAD_SHIFT) as f32;
+                avg / (1.0 + avg)
+            }
+        }
+    }
+
+    /// Returns the queue length of `hart_id` averaged over recent ticks, in
+    /// 1/256ths of a task, for callers that avoid floating point.
+    pub fn hart_load_fixed(&self, hart_id: usize) -> usize {
+        self.harts[hart_id].load_avg.load(Ordering::Relaxed)
+    }
+
+    pub fn set_priority(&self, task: &S::SchedItem, prio: isize) -> bool {
+        self.set_priority_on(H::hart_id(), task, prio)
+    }
+
+    pub(crate) fn set_priority_on(&self, hart_id: usize, task: &S::SchedItem, prio: isize) -> bool {
+        self.local_queues[hart_id].lock().set_priority(task, prio)
+    }
+
+    /// Resets the inner scheduler of `hart_id` alone, e.g. when bringing a
+    /// hart back online, and returns how many tasks were discarded.
+    ///
+    /// Any task still queued on the hart, its
+    /// [run next slot](Self::set_run_next_slots) and urgent lane included,
+    /// goes to the [sink](Self::set_task_sink), or is dropped without one,
+    /// once the locks have been released; flush it elsewhere first to keep it.
+    pub fn reinit(&self, hart_id: usize) -> usize {
+        let mut discarded = Vec::new();
+        if let Some(slot) = self.run_next.get(hart_id) {
+            Self::drain_side(slot, &mut |task| discarded.push(task));
+        }
+        #[cfg(feature = "urgent")]
+        if let Some(lane) = self.urgent.get(hart_id) {
+            Self::drain_side(lane, &mut |task| discarded.push(task));
+        }
+        let mut queue = self.local_queues[hart_id].lock();
+        while let Some(task) = queue.pick_next_task() {
+            discarded.push(task);
+        }
+        queue.init();
+        let emptied = self.harts[hart_id].len.swap(0, Ordering::Relaxed) > 0;
+        self.harts[hart_id].stats.record_len(0);
+        drop(queue);
+        self.shrunk(hart_id, emptied);
+        let count = discarded.len();
+        for task in discarded {
+            self.discard(task);
+        }
+        count
+    }
+
+    /// Visits every task queued on `hart_id`, in queue order, to age
+    /// priorities against starvation: when `f` returns a priority it is
+    /// applied with the inner `set_priority`.
+    ///
+    /// The inner schedulers cannot be iterated, so the queue is drained and
+    /// refilled under its lock, which is O(length). Refilling goes through
+    /// the inner `add_task`, which keeps the order of FIFO and RR queues and
+    /// restarts CFS tasks at the minimum vruntime of the queue, itself a boost
+    /// for long waiters. Returns the number of tasks visited.
+    pub fn age_tasks(
+        &self,
+        hart_id: usize,
+        mut f: impl FnMut(&S::SchedItem) -> Option<isize>,
+    ) -> usize {
+        let mut queue = self.local_queues[hart_id].lock();
+        let mut tasks = Vec::with_capacity(self.queue_len(hart_id));
+        while let Some(task) = queue.pick_next_task() {
+            tasks.push(task);
+        }
+        let visited = tasks.len();
+        for task in tasks {
+            if let Some(prio) = f(&task) {
+                queue.set_priority(&task, prio);
+            }
+            queue.add_task(task);
+        }
+        self.sync_len(hart_id, &queue);
+        visited
+    }
+
+    /// Moves every task queued on `from` onto `to`, returning how many were
+    /// moved. Handy to empty a hart before taking it offline.
+    ///
+    /// Both queues are locked, in index order, for the whole move. The
+    /// [run next slot](Self::set_run_next_slots) and the urgent lane of
+    /// `from` follow, one task at a time. Pinned tasks move too. Nothing
+    /// moves if `to` is offline.
+    pub fn flush_to_hart(&self, from: usize, to: usize) -> usize {
+        if from == to || !self.is_hart_online(to) {
+            return 0;
+        }
+        let (mut src, mut dst) = self.lock_pair(from, to);
+        let mut moved = 0;
+        while let Some(task) = src.pick_next_task() {
+            dst.add_task(task);
+            moved += 1;
+        }
+        let emptied = self.shrink_len(from, &src, moved);
+        self.grow_len(to, moved);
+        Self::unlock_pair(from, to, src, dst);
+        self.shrunk(from, emptied);
+        moved += self.flush_sides(from, to);
+        if moved > 0 {
+            self.notify(to);
+        }
+        moved
+    }
+
+    /// Pushes half of the imbalance between the current hart and the least
+    /// loaded online hart to the latter, returning how many tasks were moved.
+    ///
+    /// Meant to be called from an overloaded hart. Nothing moves unless the
+    /// current queue is at least two tasks longer than the target's; the
+    /// tasks moved are the ones the current queue would run next, passing
+    /// over [pinned](Self::add_task_pinned) tasks, which are put back.
+    pub fn migrate_half_to_idle(&self) -> usize {
+        let hart_id = H::hart_id();
+        let target = (0..SMP)
+            .filter(|&i| i != hart_id && self.harts[i].online.load(Ordering::Relaxed))
+            .min_by_key(|&i| self.queue_len(i));
+        let Some(to) = target else {
+            return 0;
+        };
+        if self.queue_len(hart_id) < self.queue_len(to) + 2 {
+            return 0;
+        }
+        let (mut src, mut dst) = self.lock_pair(hart_id, to);
+        // the counters may have moved before we got both locks
+        let count = self.queue_len(hart_id).saturating_sub(self.queue_len(to)) / 2;
+        let mut moved = 0;
+        let mut pinned = Vec::new();
+        while moved < count {
+            let Some(task) = src.pick_next_task() else {
+                break;
+            };
+            if self.may_migrate(&task) {
+                dst.add_task(task);
+                moved += 1;
+            } else {
+                pinned.push(task);
+            }
+        }
+        for task in pinned {
+            src.put_prev_task(task, false);
+        }
+        let emptied = self.shrink_len(hart_id, &src, moved);
+        self.grow_len(to, moved);
+        Self::unlock_pair(hart_id, to, src, dst);
+        self.shrunk(hart_id, emptied);
+        if moved > 0 {
+            self.notify(to);
+        }
+        moved
+    }
+
+    /// Changes the number of active harts to `count`, for CPU hotplug.
+    ///
+    /// This only covers part of hotplug: `SMP` is a const parameter sizing
+    /// every per-hart array, so queues are never added nor dropped and the
+    /// count can't exceed `SMP`. The queues of all `SMP` harts are allocated
+    /// up front, growing brings harts `..count` back online with whatever
+    /// their queue holds, shrinking takes harts `count..` offline and moves
+    /// their tasks, run next slots and urgent lanes included, to the least
+    /// loaded remaining harts. The queues of offline harts stay allocated.
+    /// Returns how many tasks were moved.
+    ///
+    /// Offline harts are still stolen from, so a task raced onto one after it
+    /// was drained is not lost.
+    pub fn scale_harts(&self, count: usize) -> usize {
+        assert!(
+            (1..=SMP).contains(&count),
+            "hart count {count} out of 1..={SMP}"
+        );
+        let _guard = self.scaling.lock();
+        for hart in self.harts[..count].iter() {
+            hart.online.store(true, Ordering::SeqCst);
+        }
+        let mut moved = 0;
+        for from in count..SMP {
+            self.harts[from].online.store(false, Ordering::SeqCst);
+            let to = (0..count).min_by_key(|&i| self.queue_len(i)).unwrap();
+            moved += self.flush_to_hart(from, to);
+        }
+        moved
+    }
+
+    /// Takes every queued task out of the scheduler, hart by hart, e.g. at
+    /// shutdown. See [`drain_each`](Self::drain_each) to avoid collecting
+    /// them.
+    pub fn drain(&self) -> Vec<S::SchedItem> {
+        let mut tasks = Vec::new();
+        self.drain_each(|task| tasks.push(task));
+        tasks
+    }
+
+    /// Like [`drain`](Self::drain), handing each task to `f` as it is taken
+    /// out rather than building a collection, so teardown can run the
+    /// cleanup of every task inline without allocating.
+    ///
+    /// Queues are locked per task and unlocked while `f` runs, so `f` may
+    /// call back into the scheduler; tasks it adds are drained as well if
+    /// they land on a hart not yet emptied.
+    pub fn drain_each(&self, mut f: impl FnMut(S::SchedItem)) {
+        for hart_id in 0..SMP {
+            if let Some(slot) = self.run_next.get(hart_id) {
+                Self::drain_side(slot, &mut f);
+            }
+            #[cfg(feature = "urgent")]
+            if let Some(lane) = self.urgent.get(hart_id) {
+                Self::drain_side(lane, &mut f);
+            }
+            loop {
+                let mut queue = self.local_queues[hart_id].lock();
+                let Some(task) = queue.pick_next_task() else {
+                    self.clear_len(hart_id);
+                    break;
+                };
+                let emptied = self.shrink_len(hart_id, &queue, 1);
+                drop(queue);
+                self.shrunk(hart_id, emptied);
+                f(task);
+            }
+        }
+        if let Some(overflow) = &self.overflow_queue {
+            Self::drain_side(overflow, &mut f);
+        }
+    }
+
+    /// Empties every queue like [`drain`](Self::drain), handing the tasks
+    /// to the [sink](Self::set_task_sink) or dropping them without one.
+    pub fn clear(&self) {
+        self.drain_each(|task| self.discard(task));
+    }
+
+    /// Streams the tasks of a side queue to `f`, see
+    /// [`drain_each`](Self::drain_each).
+    fn drain_side(side: &SideQueue<L, S>, f: &mut impl FnMut(S::SchedItem)) {
+        while let Some(task) = Self::pop_side(side) {
+            f(task);
+        }
+    }
+
+    /// Takes the next task of a side queue, keeping its counter in step.
+    fn pop_side(side: &SideQueue<L, S>) -> Option<S::SchedItem> {
+        let mut queue = side.queue.lock();
+        let task = queue.pick_next_task();
+        match task {
+            Some(_) => {
+                saturating_sub(&side.len, 1);
+            }
+            None => side.len.store(0, Ordering::Relaxed),
+        }
+        task
+    }
+
+    /// Moves the run next slot and the urgent lane of `from` onto `to`,
+    /// returning how many tasks were moved. The task of the slot takes the
+    /// slot of `to` if it is free, and joins its normal queue otherwise.
+    fn flush_sides(&self, from: usize, to: usize) -> usize {
+        let mut moved = 0;
+        if let (Some(src), Some(dst)) = (self.run_next.get(from), self.run_next.get(to)) {
+            if let Some(task) = Self::pop_side(src) {
+                moved += 1;
+                let mut held = dst.queue.lock();
+                if dst.len.load(Ordering::Relaxed) == 0 {
+                    held.add_task(task);
+                    dst.len.store(1, Ordering::Relaxed);
+                } else {
+                    drop(held);
+                    let mut queue = self.local_queues[to].lock();
+                    queue.add_task(task);
+                    self.grow_len(to, 1);
+                }
+            }
+        }
+        #[cfg(feature = "urgent")]
+        if let (Some(src), Some(dst)) = (self.urgent.get(from), self.urgent.get(to)) {
+            while let Some(task) = Self::pop_side(src) {
+                moved += 1;
+                dst.queue.lock().add_task(task);
+                dst.len.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        moved
+    }
+
+    /// Iterates over the harts whose length counter is non-zero.
+    ///
+    /// Counters are read lazily as the iterator advances and without
+    /// locking, so harts may gain or lose work during the iteration and the
+    /// set yielded is not a consistent snapshot.
+    pub fn harts_with_work(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..SMP).filter(|&i| self.queue_len(i) > 0)
+    }
+
+    /// Returns the number of harts currently online, see
+    /// [`scale_harts`](Self::scale_harts).
+    pub fn online_harts(&self) -> usize {
+        self.harts
+            .iter()
+            .filter(|hart| hart.online.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// Runs `f` on the inner scheduler of `hart_id`, under its lock, and
+    /// returns what it returns.
+    ///
+    /// `f` must not call back into the scheduler: re-entering any method
+    /// which locks the same queue deadlocks.
+    ///
+    /// The queue is locked exclusively even though `f` only reads, so calls
+    /// serialize; `RwSmpScheduler` (with the `rwlock` feature) lets readers
+    /// share the lock. The lengths, [`snapshot`](Self::snapshot) and the
+    /// statistics take no lock at all.
+    pub fn with_local<R>(&self, hart_id: usize, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.local_queues[hart_id].lock())
+    }
+
+    /// Like [`with_local`](Self::with_local), with mutable access, for
+    /// several changes to one queue made atomically, such as reordering and
+    /// reprioritizing it.
+    ///
+    /// The length counters are not updated, so `f` must leave the queue
+    /// holding as many tasks as it found; neither are the hooks and
+    /// statistics tied to adding and picking. Re-entering the scheduler from
+    /// `f` deadlocks as above.
+    pub fn with_local_mut<R>(&self, hart_id: usize, f: impl FnOnce(&mut S) -> R) -> R {
+        f(&mut self.local_queues[hart_id].lock())
+    }
+
+    /// Locks the queues of all harts, in index order, but only if none of
+    /// them is contended: if any `try_lock` fails, the queues locked so far
+    /// are released and `None` is returned.
+    ///
+    /// This gives a globally consistent view of the scheduler, so it only
+    /// succeeds at a quiescent moment; callers should be ready to retry or
+    /// give up. The guards are indexed by hart id, and released last to
+    /// first.
+    pub fn try_lock_all(&self) -> Option<QueueGuards<'_, L, S>> {
+        let mut guards = QueueGuards(Vec::with_capacity(SMP));
+        for queue in self.local_queues.iter() {
+            guards.0.push(queue.try_lock()?);
+        }
+        Some(guards)
+    }
+
+    /// Reads the current hart id once, for a sequence of operations on the
+    /// current hart, see [`HartContext`](crate::HartContext).
+    pub fn enter(&self) -> HartContext<'_, SMP, S, L, H> {
+        HartContext::new(self, H::hart_id())
+    }
+
+    /// Checks the invariants of the scheduler: every length counter matches
+    /// what its queue holds, and offline harts hold nothing.
+    ///
+    /// Meant for tests of scheduler extensions, at a quiescent moment: if
+    /// any queue is locked, as by [`try_lock_all`](Self::try_lock_all),
+    /// [`ValidationError::Busy`](crate::ValidationError::Busy) is returned.
+    /// The inner schedulers cannot be inspected in place, so every queue is
+    /// drained and refilled in order, which keeps CFS vruntimes but refills
+    /// RR time slices.
+    #[cfg(feature = "debug-checks")]
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut guards = self.try_lock_all().ok_or(ValidationError::Busy)?;
+        for (hart, queue) in guards.iter_mut().enumerate() {
+            let actual = Self::count_queued(queue);
+            let counted = self.queue_len(hart);
+            if actual != counted {
+                return Err(ValidationError::LenMismatch {
+                    hart,
+                    urgent: false,
+                    counted,
+                    actual,
+                });
+            }
+            if actual > 0 && !self.is_hart_online(hart) {
+                return Err(ValidationError::OfflineNotEmpty { hart, len: actual });
+            }
+        }
+        #[cfg(feature = "urgent")]
+        for (hart, lane) in self.urgent.iter().enumerate() {
+            let mut queue = lane.queue.try_lock().ok_or(ValidationError::Busy)?;
+            let actual = Self::count_queued(&mut queue);
+            let counted = lane.len.load(Ordering::Relaxed);
+            if actual != counted {
+                return Err(ValidationError::LenMismatch {
+                    hart,
+                    urgent: true,
+                    counted,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the tasks of a queue by draining and refilling it.
+    #[cfg(feature = "debug-checks")]
+    fn count_queued(queue: &mut S) -> usize {
+        let tasks = drain_queue(queue);
+        let count = tasks.len();
+        refill_queue(queue, tasks);
+        count
+    }
+
+    /// Locks the queues of two distinct harts in index order, so that paths
+    /// holding two queues at once cannot deadlock each other. The guards are
+    /// returned in argument order, to be released with
+    /// [`unlock_pair`](Self::unlock_pair).
+    fn lock_pair(
+        &self,
+        a: usize,
+        b: usize,
+    ) -> (
+        lock_api::MutexGuard<'_, L, S>,
+        lock_api::MutexGuard<'_, L, S>,
+    ) {
+        assert_ne!(a, b);
+        if a < b {
+            let first = self.local_queues[a].lock();
+            (first, self.local_queues[b].lock())
+        } else {
+            let first = self.local_queues[b].lock();
+            (self.local_queues[a].lock(), first)
+        }
+    }
+
+    /// Releases the guards of [`lock_pair`](Self::lock_pair) for `a` and `b`
+    /// in the reverse order they were locked, as `IrqSafeMutex` needs.
+    fn unlock_pair(
+        a: usize,
+        b: usize,
+        guard_a: lock_api::MutexGuard<'_, L, S>,
+        guard_b: lock_api::MutexGuard<'_, L, S>,
+    ) {
+        if a < b {
+            drop(guard_b);
+            drop(guard_a);
+        } else {
+            drop(guard_a);
+            drop(guard_b);
+        }
+    }
+
+    /// Gives every hart a run next slot, `slots[i]` being the inner
+    /// scheduler holding the one task of hart `i`'s slot.
+    ///
+    /// A task put in the slot with [`add_task_run_next`](Self::add_task_run_next)
+    /// is picked before anything else on its hart, urgent lanes included,
+    /// and is never stolen. Slots set after [`init`](Self::init) are
+    /// initialized right away.
+    pub fn set_run_next_slots(&mut self, slots: Vec<S>) {
+        assert_eq!(slots.len(), SMP);
+        // init() would not run again for them
+        let initialized = *self.initialized.get_mut();
+        self.run_next = slots
+            .into_iter()
+            .map(|mut slot| {
+                if initialized {
+                    slot.init();
+                }
+                SideQueue {
+                    queue: lock_api::Mutex::new(slot),
+                    len: AtomicUsize::new(0),
+                }
+            })
+            .collect();
+    }
+
+    /// Puts `task` in the run next slot of the current hart, so that it is
+    /// the next task the hart picks, say a handler which was just unblocked.
+    /// A task already in the slot moves to the back of the normal queue.
+    ///
+    /// Panics if no slots were [set](Self::set_run_next_slots).
+    pub fn add_task_run_next(&self, task: S::SchedItem) {
+        self.check_init();
+        let current = H::hart_id();
+        let hart_id = self.online_or_least_loaded(current);
+        let slot = self
+            .run_next
+            .get(hart_id)
+            .expect("add_task_run_next() needs run next slots, see set_run_next_slots()");
+        self.log(hart_id, ReplayOp::Add, &task);
+        let mut held = slot.queue.lock();
+        let prev = held.pick_next_task();
+        held.add_task(task);
+        slot.len.store(1, Ordering::Relaxed);
+        drop(held);
+        self.harts[hart_id].stats.record_enqueue();
+        if let Some(prev) = prev {
+            // already logged and counted as enqueued when it took the slot
+            let mut queue = self.local_queues[hart_id].lock();
+            queue.add_task(prev);
+            self.grow_len(hart_id, 1);
+        }
+        self.notify_as(current, hart_id);
+    }
+
+    /// Returns the number of tasks in the run next slot of `hart_id`, zero
+    /// or one, which [`queue_len`](Self::queue_len) does not count.
+    pub fn run_next_len(&self, hart_id: usize) -> usize {
+        self.run_next
+            .get(hart_id)
+            .map_or(0, |slot| slot.len.load(Ordering::Relaxed))
+    }
+
+    fn pick_run_next(&self, hart_id: usize) -> Option<S::SchedItem> {
+        let slot = self.run_next.get(hart_id)?;
+        if slot.len.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let mut held = slot.queue.lock();
+        let task = held.pick_next_task();
+        slot.len.store(0, Ordering::Relaxed);
+        drop(held);
+        let task = task?;
+        self.log(hart_id, ReplayOp::Pick, &task);
+        self.harts[hart_id].stats.record_dequeue();
+        self.harts[hart_id].stats.record_pick();
+        Some(task)
+    }
+
+    /// Gives every hart an urgent lane, `lanes[i]` being the one of hart `i`.
+    ///
+    /// Tasks added with [`add_urgent_task`](Self::add_urgent_task) are picked
+    /// before any task of the normal queue, and thieves look at the urgent
+    /// lanes of their victims before the normal queues. Lanes set after
+    /// [`init`](Self::init) are initialized right away.
+    #[cfg(feature = "urgent")]
+    pub fn set_urgent_lanes(&mut self, lanes: Vec<S>) {
+        assert_eq!(lanes.len(), SMP);
+        // init() would not run again for them
+        let initialized = *self.initialized.get_mut();
+        self.urgent = lanes
+            .into_iter()
+            .map(|mut lane| {
+                if initialized {
+                    lane.init();
+                }
+                lane
+            })
+            .map(|lane| SideQueue {
+                queue: lock_api::Mutex::new(lane),
+                len: AtomicUsize::new(0),
+            })
+            .collect();
+    }
+
+    /// Adds a task to the urgent lane of the current hart.
+    ///
+    /// Panics if no lanes were [set](Self::set_urgent_lanes).
+    #[cfg(feature = "urgent")]
+    pub fn add_urgent_task(&self, task: S::SchedItem) {
+        self.add_urgent_task_on(H::hart_id(), task);
+    }
+
+    /// Adds a task to the urgent lane of `hart_id` instead of the current
+    /// hart.
+    #[cfg(feature = "urgent")]
+    pub fn add_urgent_task_on(&self, hart_id: usize, task: S::SchedItem) {
+        self.check_init();
+        let hart_id = self.online_or_least_loaded(hart_id);
+        let lane = self
+            .urgent
+            .get(hart_id)
+            .expect("add_urgent_task() needs urgent lanes, see set_urgent_lanes()");
+        self.log(hart_id, ReplayOp::Add, &task);
+        lane.queue.lock().add_task(task);
+        lane.len.fetch_add(1, Ordering::Relaxed);
+        self.harts[hart_id].stats.record_enqueue();
+        self.notify(hart_id);
+    }
+
+    /// Returns the number of tasks currently in the urgent lane of
+    /// `hart_id`, which [`queue_len`](Self::queue_len) does not count.
+    #[cfg(feature = "urgent")]
+    pub fn urgent_len(&self, hart_id: usize) -> usize {
+        self.urgent
+            .get(hart_id)
+            .map_or(0, |lane| lane.len.load(Ordering::Relaxed))
+    }
+
+    #[cfg(feature = "urgent")]
+    fn pick_urgent(&self, hart_id: usize) -> Option<S::SchedItem> {
+        let lane = self.urgent.get(hart_id)?;
+        if lane.len.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        self.check_init();
+        let mut queue = lane.queue.lock();
+        let Some(task) = queue.pick_next_task() else {
+            lane.len.store(0, Ordering::Relaxed);
+            return None;
+        };
+        saturating_sub(&lane.len, 1);
+        drop(queue);
+        self.log(hart_id, ReplayOp::Pick, &task);
+        self.harts[hart_id].stats.record_dequeue();
+        self.harts[hart_id].stats.record_pick();
+        Some(task)
+    }
+
+    /// Steals the head of some other hart's urgent lane, in a single pass
+    /// over the victims with the same rules as [`steal`](Self::steal).
+    #[cfg(feature = "urgent")]
+    fn steal_urgent(
+        &self,
+        hart_id: usize,
+        mut allow: impl FnMut(&S::SchedItem, usize) -> bool,
+    ) -> Option<(usize, S::SchedItem)> {
+        for (i, lane) in self.urgent.iter().enumerate() {
+            if i == hart_id || lane.len.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let Some(mut other) = lane.queue.try_lock() else {
+                self.harts[hart_id].stats.record_contention();
+                continue;
+            };
+            let stolen = self.take_stolen(&mut other, |task| {
+                self.may_steal(task, i, hart_id) && allow(task, i)
+            });
+            let task = match stolen {
+                Ok(task) => task,
+                Err(miss) => {
+                    if miss == StealMiss::Empty {
+                        lane.len.store(0, Ordering::Relaxed);
+                    }
+                    continue;
+                }
+            };
+            saturating_sub(&lane.len, 1);
+            drop(other);
+            self.stolen(i, hart_id, &task);
+            return Some((i, task));
+        }
+        None
+    }
+
+    /// Records a decision in the replay log and fires its tracepoint.
+    #[inline]
+    fn log(&self, _hart_id: usize, _op: ReplayOp, _task: &S::SchedItem) {
+        #[cfg(feature = "replay")]
+        self.replay.record(_hart_id, _op, (self.task_id)(_task));
+        match _op {
+            ReplayOp::Add => trace_sched!(enqueue, _hart_id, (self.task_id)(_task)),
+            ReplayOp::Pick => trace_sched!(pick, _hart_id, (self.task_id)(_task)),
+            ReplayOp::Steal { from: _from } => {
+                trace_sched!(steal, _from, _hart_id, (self.task_id)(_task))
+            }
+            ReplayOp::Block => {}
+        }
+    }
+
+    /// Sets how tasks are identified in the replay log and in tracepoints.
+    /// By default every task is recorded as 0.
+    #[cfg(any(feature = "replay", feature = "trace"))]
+    pub fn set_task_id(&mut self, id: fn(&S::SchedItem) -> usize) {
+        self.task_id = id;
+    }
+
+    /// Returns the last [`REPLAY_LEN`](crate::REPLAY_LEN) adds, picks and
+    /// steals, oldest first.
+    ///
+    /// Decisions are numbered by a shared atomic counter when they are
+    /// recorded, which happens right after they are made, so entries of
+    /// different harts are ordered by when they were logged. Entries written
+    /// concurrently with this call may be missing.
+    #[cfg(feature = "replay")]
+    pub fn replay_log(&self) -> Vec<ReplayEntry> {
+        self.replay.entries()
+    }
+
+    /// Returns the number of tasks currently queued on `hart_id`.
+    pub fn queue_len(&self, hart_id: usize) -> usize {
+        self.harts[hart_id].len.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of tasks ever added to `hart_id` through
+    /// [`add_task`](Self::add_task) or [`add_task_on`](Self::add_task_on).
+    ///
+    /// Unlike [`queue_len`](Self::queue_len) this never decreases, and it is
+    /// left alone by [`reset_stats`](Self::reset_stats). Like all counters it
+    /// needs the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn total_enqueued(&self, hart_id: usize) -> usize {
+        self.harts[hart_id].stats.enqueued()
+    }
+
+    /// Returns the number of tasks ever picked from the queue of `hart_id`,
+    /// whether by the hart itself or by a thief.
+    ///
+    /// Unlike [`queue_len`](Self::queue_len) this never decreases, and it is
+    /// left alone by [`reset_stats`](Self::reset_stats). Like all counters it
+    /// needs the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn total_dequeued(&self, hart_id: usize) -> usize {
+        self.harts[hart_id].stats.dequeued()
+    }
+
+    /// Returns the name of `hart_id`, if [given](Self::with_hart_names).
+    pub fn hart_name(&self, hart_id: usize) -> Option<&'static str> {
+        self.harts[hart_id].name
+    }
+
+    /// Returns the hart `hart_id` last stole a task from, if it ever stole.
+    pub fn last_steal_victim(&self, hart_id: usize) -> Option<usize> {
+        match self.harts[hart_id].last_victim.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            victim => Some(victim),
+        }
+    }
+
+    /// Returns the statistics recorded for `hart_id`, which needs the
+    /// `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self, hart_id: usize) -> HartStats {
+        self.harts[hart_id].stats.load()
+    }
+
+    /// Captures the lengths, online flags and statistics of all harts without
+    /// taking any lock.
+    pub fn snapshot(&self) -> SchedSnapshot<SMP> {
+        let harts: [HartSnapshot; SMP] = core::array::from_fn(|i| self.harts[i].snapshot(i));
+        SchedSnapshot {
+            total: wrapping_sum(harts.iter().map(|hart| hart.len)),
+            harts,
+        }
+    }
+
+    /// Measures how evenly the queued tasks are spread over the online
+    /// harts, reading each length counter once without locking.
+    pub fn fairness_report(&self) -> FairnessReport {
+        let lens: Vec<(usize, usize)> = (0..SMP)
+            .filter(|&i| self.is_hart_online(i))
+            .map(|i| (self.queue_len(i), i))
+            .collect();
+        let (Some(&min), Some(&max)) = (lens.iter().min(), lens.iter().max()) else {
+            return FairnessReport::default();
+        };
+        let total: usize = lens.iter().map(|&(len, _)| len).sum();
+        // scaled by the number of harts to keep the mean exact
+        let scaled: usize = lens
+            .iter()
+            .map(|&(len, _)| (len * lens.len()).abs_diff(total))
+            .sum();
+        FairnessReport {
+            online: lens.len(),
+            total,
+            min,
+            max,
+            deviation: scaled / lens.len(),
+        }
+    }
+
+    /// Returns the longest queue length minus the shortest among the online
+    /// harts, a cheap signal for when to rebalance, see
+    /// [`fairness_report`](Self::fairness_report).
+    pub fn imbalance(&self) -> usize {
+        self.fairness_report().spread()
+    }
+
+    /// Returns the id of the scheduler in the live registry, see
+    /// [`for_each_scheduler`](crate::for_each_scheduler).
+    #[cfg(feature = "smp-debug")]
+    pub fn debug_id(&self) -> usize {
+        self.debug_id
+    }
+
+    /// Returns the number of steals recorded across all harts.
+    ///
+    /// Each hart's counter is read on its own, so under concurrency the total
+    /// is a racy snapshot: it never runs ahead of the real count, but may miss
+    /// steals that happen while it is being summed. Like the counters it wraps
+    /// around on overflow.
+    #[cfg(feature = "metrics")]
+    pub fn total_steals(&self) -> usize {
+        wrapping_sum((0..SMP).map(|i| self.stats(i).steals))
+    }
+
+    /// Returns the number of picks recorded across all harts, stolen tasks
+    /// included. Like [`total_steals`](Self::total_steals) this is a racy
+    /// snapshot.
+    #[cfg(feature = "metrics")]
+    pub fn total_picks(&self) -> usize {
+        wrapping_sum((0..SMP).map(|i| self.stats(i).picks))
+    }
+
+    /// Returns how many tasks `hart_id` put back with `preempt` set since
+    /// the stats were last [reset](Self::reset_stats), to tell whether a
+    /// workload is preemption-heavy.
+    ///
+    /// A [`task_tick`](Self::task_tick) asking for a reschedule is counted
+    /// once the caller puts the preempted task back, so each preemption
+    /// counts once. Like the other statistics this needs the `metrics`
+    /// feature.
+    #[cfg(feature = "metrics")]
+    pub fn preempt_count(&self, hart_id: usize) -> usize {
+        self.harts[hart_id].stats.load().preemptions
+    }
+
+    /// Returns the lowest and highest length the queue of `hart_id` had
+    /// since the scheduler was created or the stats were last
+    /// [reset](Self::reset_stats).
+    ///
+    /// Like the other statistics this needs the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn watermark(&self, hart_id: usize) -> (usize, usize) {
+        self.harts[hart_id].stats.watermark()
+    }
+
+    /// Zeroes the statistics of every hart, and starts the
+    /// [watermarks](Self::watermark) over from the current lengths.
+    ///
+    /// Counters are cleared one after another rather than all at once, so an
+    /// operation running concurrently may be counted partly before and partly
+    /// after the reset.
+    #[cfg(feature = "metrics")]
+    pub fn reset_stats(&self) {
+        for hart in self.harts.iter() {
+            hart.stats.reset(hart.len.load(Ordering::Relaxed));
+        }
+    }
+}
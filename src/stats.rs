@@ -0,0 +1,253 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A copy of the counters recorded on behalf of one hart.
+///
+/// Counters are only kept with the `metrics` feature, and without it there
+/// is nothing to read: this type goes away along with the accessors, such
+/// as `stats()` and `watermark()`, rather than reading zero. They wrap around on overflow, which a busy 32-bit system can
+/// reach: compare two readings with `wrapping_sub` to get the count in
+/// between.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HartStats {
+    /// tasks picked by the hart, stolen ones included
+    pub picks: usize,
+    /// tasks the hart stole from other harts
+    pub steals: usize,
+    /// victim locks the hart failed to take while stealing
+    pub contention: usize,
+    /// tasks put back on the hart after being preempted
+    pub preemptions: usize,
+}
+
+/// The state of one hart as captured by a [`SchedSnapshot`].
+///
+/// It debug-prints as the hart's name, or `hart{id}` if it has none.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct HartSnapshot {
+    /// the index of the hart
+    pub id: usize,
+    /// the name of the hart, if one was given
+    pub name: Option<&'static str>,
+    /// tasks queued on the hart
+    pub len: usize,
+    /// whether the hart was online
+    pub online: bool,
+    /// the counters of the hart
+    #[cfg(feature = "metrics")]
+    pub stats: HartStats,
+}
+
+impl fmt::Debug for HartSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut hart = f.debug_struct("HartSnapshot");
+        match self.name {
+            Some(name) => hart.field("name", &name),
+            None => hart.field("name", &format_args!("hart{}", self.id)),
+        };
+        hart.field("len", &self.len).field("online", &self.online);
+        #[cfg(feature = "metrics")]
+        hart.field("stats", &self.stats);
+        hart.finish()
+    }
+}
+
+/// A plain copy of the state of a whole scheduler, for logs and crash dumps.
+///
+/// It is assembled from independent atomic loads, so under concurrency the
+/// harts are not captured at quite the same instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedSnapshot<const SMP: usize> {
+    /// the state of every hart, indexed by hart id
+    pub harts: [HartSnapshot; SMP],
+    /// tasks queued across all harts
+    pub total: usize,
+}
+
+/// How evenly the queued tasks are spread over the online harts, see
+/// `fairness_report`.
+///
+/// Like a [`SchedSnapshot`] it is assembled from independent atomic loads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FairnessReport {
+    /// harts which were online, the only ones accounted
+    pub online: usize,
+    /// tasks queued across the online harts
+    pub total: usize,
+    /// the shortest queue, and the hart holding it
+    pub min: (usize, usize),
+    /// the longest queue, and the hart holding it
+    pub max: (usize, usize),
+    /// the sum of the distances of every length from the mean, in tasks
+    pub deviation: usize,
+}
+
+impl FairnessReport {
+    /// The longest queue minus the shortest, zero when perfectly balanced.
+    pub fn spread(&self) -> usize {
+        self.max.0 - self.min.0
+    }
+
+    /// The mean queue length, rounded down.
+    pub fn mean(&self) -> usize {
+        self.total.checked_div(self.online).unwrap_or(0)
+    }
+}
+
+/// Sums counters the way they are kept, wrapping around on overflow.
+pub(crate) fn wrapping_sum(counters: impl Iterator<Item = usize>) -> usize {
+    counters.fold(0, usize::wrapping_add)
+}
+
+/// Subtracts `n` from a length counter, stopping at zero should it have
+/// drifted below the real length, and returns the previous value.
+///
+/// Lengths are gauges rather than running totals, so unlike the other
+/// counters they must never wrap.
+pub(crate) fn saturating_sub(len: &AtomicUsize, n: usize) -> usize {
+    len.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+        Some(len.saturating_sub(n))
+    })
+    .unwrap()
+}
+
+/// The live counters behind [`HartStats`].
+///
+/// All of them are bumped with `fetch_add`, which wraps on overflow.
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+pub(crate) struct HartCounters {
+    picks: AtomicUsize,
+    steals: AtomicUsize,
+    contention: AtomicUsize,
+    preemptions: AtomicUsize,
+    enqueued: AtomicUsize,
+    dequeued: AtomicUsize,
+    // the length watermarks, which are gauges rather than running totals
+    min_len: AtomicUsize,
+    max_len: AtomicUsize,
+}
+
+#[cfg(feature = "metrics")]
+impl HartCounters {
+    pub fn record_pick(&self) {
+        self.picks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_steal(&self) {
+        self.steals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_contention(&self) {
+        self.contention.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_preempt(&self) {
+        self.preemptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_enqueue(&self) {
+        self.enqueued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dequeue(&self) {
+        self.dequeued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_len(&self, len: usize) {
+        // most updates move neither mark, spare them the write
+        if len < self.min_len.load(Ordering::Relaxed) {
+            self.min_len.fetch_min(len, Ordering::Relaxed);
+        }
+        if len > self.max_len.load(Ordering::Relaxed) {
+            self.max_len.fetch_max(len, Ordering::Relaxed);
+        }
+    }
+
+    pub fn watermark(&self) -> (usize, usize) {
+        (
+            self.min_len.load(Ordering::Relaxed),
+            self.max_len.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn enqueued(&self) -> usize {
+        self.enqueued.load(Ordering::Relaxed)
+    }
+
+    pub fn dequeued(&self) -> usize {
+        self.dequeued.load(Ordering::Relaxed)
+    }
+
+    pub fn load(&self) -> HartStats {
+        HartStats {
+            picks: self.picks.load(Ordering::Relaxed),
+            steals: self.steals.load(Ordering::Relaxed),
+            contention: self.contention.load(Ordering::Relaxed),
+            preemptions: self.preemptions.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn restore(&self, other: &HartCounters) {
+        let stats = other.load();
+        self.picks.store(stats.picks, Ordering::Relaxed);
+        self.steals.store(stats.steals, Ordering::Relaxed);
+        self.contention.store(stats.contention, Ordering::Relaxed);
+        self.preemptions.store(stats.preemptions, Ordering::Relaxed);
+        self.enqueued.store(other.enqueued(), Ordering::Relaxed);
+        self.dequeued.store(other.dequeued(), Ordering::Relaxed);
+        let (min, max) = other.watermark();
+        self.min_len.store(min, Ordering::Relaxed);
+        self.max_len.store(max, Ordering::Relaxed);
+    }
+
+    /// Starts every counter at `n`, to test the wrap around.
+    #[cfg(test)]
+    pub fn starting_at(n: usize) -> Self {
+        Self {
+            picks: AtomicUsize::new(n),
+            steals: AtomicUsize::new(n),
+            contention: AtomicUsize::new(n),
+            preemptions: AtomicUsize::new(n),
+            enqueued: AtomicUsize::new(n),
+            dequeued: AtomicUsize::new(n),
+            min_len: AtomicUsize::new(0),
+            max_len: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn reset(&self, len: usize) {
+        self.picks.store(0, Ordering::Relaxed);
+        self.steals.store(0, Ordering::Relaxed);
+        self.contention.store(0, Ordering::Relaxed);
+        self.preemptions.store(0, Ordering::Relaxed);
+        self.min_len.store(len, Ordering::Relaxed);
+        self.max_len.store(len, Ordering::Relaxed);
+    }
+}
+
+/// Without the `metrics` feature the counters are gone: recording compiles
+/// to nothing, and nothing can be read back.
+#[cfg(not(feature = "metrics"))]
+#[derive(Default)]
+pub(crate) struct HartCounters {}
+
+#[cfg(not(feature = "metrics"))]
+impl HartCounters {
+    pub fn record_pick(&self) {}
+
+    pub fn record_steal(&self) {}
+
+    pub fn record_contention(&self) {}
+
+    pub fn record_preempt(&self) {}
+
+    pub fn record_enqueue(&self) {}
+
+    pub fn record_dequeue(&self) {}
+
+    pub fn record_len(&self, _len: usize) {}
+
+    pub fn restore(&self, _other: &HartCounters) {}
+}
@@ -0,0 +1,154 @@
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Per-task bookkeeping used by the tracked scheduling paths.
+///
+/// Embed one in the task type and implement [`HasTaskMeta`] for it, the
+/// scheduler reaches it through the `Arc` and the inner task wrapper.
+#[derive(Debug)]
+pub struct TaskMeta {
+    steals: AtomicUsize,
+    // the hart the task was last stolen from, and when
+    stolen_from: AtomicUsize,
+    stolen_at: AtomicUsize,
+    class: AtomicUsize,
+    last_hart: AtomicUsize,
+    // 0 when unrestricted
+    affinity: AtomicU64,
+    pinned: AtomicBool,
+}
+
+impl Default for TaskMeta {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskMeta {
+    /// Creates a new [`TaskMeta`].
+    pub const fn new() -> Self {
+        Self {
+            steals: AtomicUsize::new(0),
+            stolen_from: AtomicUsize::new(usize::MAX),
+            stolen_at: AtomicUsize::new(0),
+            class: AtomicUsize::new(usize::MAX),
+            last_hart: AtomicUsize::new(usize::MAX),
+            affinity: AtomicU64::new(0),
+            pinned: AtomicBool::new(false),
+        }
+    }
+
+    /// How many times the task was stolen since it was last picked from the
+    /// queue it sat on.
+    pub fn steal_count(&self) -> usize {
+        self.steals.load(Ordering::Relaxed)
+    }
+
+    /// The hart the task was last stolen from, if it was ever stolen.
+    pub fn stolen_from(&self) -> Option<usize> {
+        match self.stolen_from.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            hart_id => Some(hart_id),
+        }
+    }
+
+    /// The hart which last picked the task through the tracked paths, whose
+    /// caches are most likely still warm.
+    pub fn last_hart(&self) -> Option<usize> {
+        match self.last_hart.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            hart_id => Some(hart_id),
+        }
+    }
+
+    pub(crate) fn record_steal(&self, from: usize, to: usize, now: usize) {
+        self.last_hart.store(to, Ordering::Relaxed);
+        self.steals.fetch_add(1, Ordering::Relaxed);
+        self.stolen_from.store(from, Ordering::Relaxed);
+        self.stolen_at.store(now, Ordering::Relaxed);
+    }
+
+    /// Returns true if `hart_id` lost the task to a thief less than `window`
+    /// ticks before `now`.
+    pub(crate) fn stolen_from_within(&self, hart_id: usize, now: usize, window: usize) -> bool {
+        self.stolen_from() == Some(hart_id)
+            && now.wrapping_sub(self.stolen_at.load(Ordering::Relaxed)) < window
+    }
+
+    /// The class the task was added with through `add_task_classed`.
+    pub fn class(&self) -> Option<usize> {
+        match self.class.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            class => Some(class),
+        }
+    }
+
+    pub(crate) fn set_class(&self, class: usize) {
+        self.class.store(class, Ordering::Relaxed);
+    }
+
+    /// The harts the task may run on, one bit per hart, as set through
+    /// `add_task_with_affinity`. `None` means unrestricted.
+    pub fn affinity(&self) -> Option<u64> {
+        match self.affinity.load(Ordering::Relaxed) {
+            0 => None,
+            mask => Some(mask),
+        }
+    }
+
+    pub(crate) fn set_affinity(&self, mask: u64) {
+        self.affinity.store(mask, Ordering::Relaxed);
+    }
+
+    /// Whether thieves may take the task, true unless it was pinned, e.g.
+    /// through `add_task_pinned`.
+    pub fn is_migratable(&self) -> bool {
+        !self.pinned.load(Ordering::Relaxed)
+    }
+
+    /// Pins the task to the hart it is queued on, or lets it migrate again.
+    /// A cheaper alternative to an affinity mask when all that matters is
+    /// whether the task moves.
+    ///
+    /// The untracked picks only check this once the scheduler has pinned a
+    /// task through `add_task_pinned`.
+    pub fn set_migratable(&self, migratable: bool) {
+        self.pinned.store(!migratable, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_local_pick(&self, hart_id: usize) {
+        self.last_hart.store(hart_id, Ordering::Relaxed);
+        self.steals.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The trait for getting the [`TaskMeta`] of a task
+pub trait HasTaskMeta {
+    /// get the task meta
+    fn task_meta(&self) -> &TaskMeta;
+}
+
+impl<P: Deref> HasTaskMeta for P
+where
+    P::Target: HasTaskMeta,
+{
+    fn task_meta(&self) -> &TaskMeta {
+        self.deref().task_meta()
+    }
+}
+
+/// A stable identity for tasks, for matching them by id rather than by
+/// address, e.g. when a handle to the task was rebuilt rather than cloned.
+pub trait TaskId {
+    /// get the id of the task, unique among the live tasks
+    fn id(&self) -> u64;
+}
+
+impl<P: Deref> TaskId for P
+where
+    P::Target: TaskId,
+{
+    fn id(&self) -> u64 {
+        self.deref().id()
+    }
+}
@@ -0,0 +1,62 @@
+//! Static tracepoints at the scheduling boundaries.
+//!
+//! With the `trace` feature every enqueue, pick and steal fires the matching
+//! probe of the registered [`Tracepoints`], passing the hart and the task id
+//! set with `set_task_id`. Without the feature the tracepoints expand to
+//! nothing.
+
+#[cfg(feature = "trace")]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Fires a tracepoint, compiled out without the `trace` feature.
+///
+/// `trace_sched!(enqueue, hart, task)` and `trace_sched!(pick, hart, task)`
+/// take the hart and the task id, `trace_sched!(steal, from, to, task)` the
+/// victim, the thief and the task id. The arguments are not evaluated when
+/// tracing is disabled.
+macro_rules! trace_sched {
+    ($probe:ident, $($arg:expr),+ $(,)?) => {{
+        #[cfg(feature = "trace")]
+        if let Some(probes) = $crate::trace::probes() {
+            (probes.$probe)($($arg),+);
+        }
+    }};
+}
+pub(crate) use trace_sched;
+
+/// The probes called by the tracepoints, such as `sched_enqueue` in a
+/// kernel's tracing infrastructure.
+#[cfg(feature = "trace")]
+#[derive(Debug, Clone, Copy)]
+pub struct Tracepoints {
+    /// `(hart, task)`: a task was queued on `hart`
+    pub enqueue: fn(usize, usize),
+    /// `(hart, task)`: `hart` picked a task from its own queue
+    pub pick: fn(usize, usize),
+    /// `(from, to, task)`: hart `to` stole a task from hart `from`
+    pub steal: fn(usize, usize, usize),
+}
+
+#[cfg(feature = "trace")]
+static PROBES: AtomicPtr<Tracepoints> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the probes for all schedulers, replacing any earlier ones.
+///
+/// Harts already inside a tracepoint may still call the old probes.
+#[cfg(feature = "trace")]
+pub fn register_tracepoints(probes: &'static Tracepoints) {
+    PROBES.store(probes as *const _ as *mut _, Ordering::Release);
+}
+
+/// Unregisters the probes, tracepoints do nothing afterwards.
+#[cfg(feature = "trace")]
+pub fn unregister_tracepoints() {
+    PROBES.store(core::ptr::null_mut(), Ordering::Release);
+}
+
+#[cfg(feature = "trace")]
+#[inline]
+pub(crate) fn probes() -> Option<&'static Tracepoints> {
+    // SAFETY: only null or pointers from a `&'static Tracepoints` are stored
+    unsafe { PROBES.load(Ordering::Acquire).as_ref() }
+}
@@ -0,0 +1,128 @@
+use crate::smp::SmpScheduler;
+use crate::ScheduleHart;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use scheduler::{BaseScheduler, FifoTask};
+
+/// A FIFO scheduler whose ready queue only holds [`Weak`] references.
+///
+/// The queue does not keep tasks alive: a task whose last [`Arc`] is dropped
+/// elsewhere is silently discarded the next time it reaches the head of the
+/// queue, both when picked locally and when stolen by another hart.
+pub struct WeakFifoScheduler<T> {
+    ready_queue: VecDeque<Weak<FifoTask<T>>>,
+}
+
+impl<T> WeakFifoScheduler<T> {
+    /// Creates a new empty [`WeakFifoScheduler`].
+    pub const fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+
+    /// get the name of scheduler
+    pub fn scheduler_name() -> &'static str {
+        "Weak FIFO"
+    }
+
+    /// Returns the number of queued tasks which are still alive, in
+    /// O(length).
+    pub fn live_len(&self) -> usize {
+        self.ready_queue
+            .iter()
+            .filter(|task| task.strong_count() > 0)
+            .count()
+    }
+}
+
+impl<T> BaseScheduler for WeakFifoScheduler<T> {
+    type SchedItem = Arc<FifoTask<T>>;
+
+    fn init(&mut self) {}
+
+    fn add_task(&mut self, task: Self::SchedItem) {
+        self.ready_queue.push_back(Arc::downgrade(&task));
+    }
+
+    fn remove_task(&mut self, task: &Self::SchedItem) -> Option<Self::SchedItem> {
+        let idx = self
+            .ready_queue
+            .iter()
+            .position(|t| core::ptr::eq(t.as_ptr(), Arc::as_ptr(task)))?;
+        self.ready_queue.remove(idx);
+        // drop the dead entries while we are scanning the queue anyway
+        self.ready_queue.retain(|t| t.strong_count() > 0);
+        Some(task.clone())
+    }
+
+    fn pick_next_task(&mut self) -> Option<Self::SchedItem> {
+        while let Some(task) = self.ready_queue.pop_front() {
+            if let Some(task) = task.upgrade() {
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    fn put_prev_task(&mut self, prev: Self::SchedItem, _preempt: bool) {
+        self.ready_queue.push_back(Arc::downgrade(&prev));
+    }
+
+    fn task_tick(&mut self, _current: &Self::SchedItem) -> bool {
+        false // no reschedule
+    }
+
+    fn set_priority(&mut self, _task: &Self::SchedItem, _prio: isize) -> bool {
+        false
+    }
+}
+
+/// A FIFO smpscheduler whose queues hold [`Weak`] task references.
+///
+/// Callers own the tasks: the scheduler only hands out tasks that are still
+/// alive somewhere else. Since dead tasks leave the queues without being
+/// picked, the length counters are recounted with
+/// [`live_len`](WeakFifoScheduler::live_len) whenever tasks leave a queue,
+/// which makes picks O(length).
+pub struct WeakFifoSmpScheduler<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> {
+    inner: SmpScheduler<SMP, WeakFifoScheduler<T>, L, H>,
+}
+
+impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart>
+    WeakFifoSmpScheduler<SMP, T, L, H>
+{
+    /// The number of harts, usable in const contexts.
+    pub const NR_HARTS: usize = SMP;
+
+    /// Creates a new empty [`WeakFifoScheduler`].
+    pub fn new() -> Self {
+        let mut schedulers = Vec::new();
+        for _ in 0..SMP {
+            schedulers.push(WeakFifoScheduler::new());
+        }
+        let mut inner = SmpScheduler::new(schedulers);
+        inner.set_count(WeakFifoScheduler::live_len);
+        Self { inner }
+    }
+}
+
+impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> Deref
+    for WeakFifoSmpScheduler<SMP, T, L, H>
+{
+    type Target = SmpScheduler<SMP, WeakFifoScheduler<T>, L, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<const SMP: usize, T, L: lock_api::RawMutex, H: ScheduleHart> DerefMut
+    for WeakFifoSmpScheduler<SMP, T, L, H>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}